@@ -0,0 +1,81 @@
+// Audio capture -> processing thread throughput, per-sample mpsc channel vs block ring buffer
+// Run with `cargo run --release --example audio_throughput`
+// CPU time matters more than wall time here, since the ring buffer sleeps instead of spinning when full/empty
+#[path = "../src/audio_buffer.rs"]
+#[allow(dead_code)]
+mod audio_buffer;
+
+use std::time::Instant;
+
+const SAMPLES: usize = 16_000 * 60 * 10; // 10 minutes of 16kHz audio
+const CALLBACK_SIZE: usize = 512; // samples per capture callback
+const READ_SIZE: usize = 8_096; // samples per read (carryover_buffer_size in casl.json)
+
+fn main() {
+    let block: Vec<i16> = (0..CALLBACK_SIZE).map(|i| i as i16).collect();
+
+    // before: every sample is sent and received on its own
+    let (tx, rx) = std::sync::mpsc::channel::<i16>();
+    let start = (Instant::now(), cpu_time());
+    let producer = std::thread::spawn(move || {
+        for _ in 0..SAMPLES / CALLBACK_SIZE {
+            for &sample in &block {
+                tx.send(sample).unwrap();
+            }
+        }
+    });
+    let mut buffer = Vec::with_capacity(READ_SIZE);
+    let mut received = 0;
+    while let Ok(sample) = rx.recv() {
+        buffer.push(sample);
+        if buffer.len() == READ_SIZE {
+            received += buffer.len();
+            buffer.clear();
+        }
+    }
+    received += buffer.len();
+    producer.join().unwrap();
+    report("mpsc channel (per sample)", received, start);
+
+    // after: whole callback blocks go through the ring buffer
+    let block: Vec<i16> = (0..CALLBACK_SIZE).map(|i| i as i16).collect();
+    let (mut tx, mut rx) = audio_buffer::audio_buffer(64_000);
+    let start = (Instant::now(), cpu_time());
+    let producer = std::thread::spawn(move || {
+        for _ in 0..SAMPLES / CALLBACK_SIZE {
+            tx.send_block_blocking(&block);
+        }
+    });
+    let mut buffer = Vec::with_capacity(READ_SIZE);
+    let mut received = 0;
+    while rx.recv_block(&mut buffer, READ_SIZE).is_ok() {
+        // (read size is approximate, like in speech::process_audio_loop)
+        if buffer.len() >= READ_SIZE {
+            received += buffer.len();
+            buffer.clear();
+        }
+    }
+    received += buffer.len();
+    producer.join().unwrap();
+    report("ring buffer (per block)", received, start);
+}
+
+fn report(name: &str, samples: usize, start: (Instant, Option<f64>)) {
+    let elapsed = start.0.elapsed().as_secs_f64();
+    println!("{}: {} samples in {:.3}s ({:.1}M samples/s, {:.0}x realtime)",
+             name, samples, elapsed, samples as f64 / elapsed / 1e6, samples as f64 / elapsed / 16_000.0);
+    if let (Some(before), Some(after)) = (start.1, cpu_time()) {
+        let cpu = after - before;
+        println!("    {:.2}s CPU time ({:.3}% of one core at realtime)", cpu, cpu / (samples as f64 / 16_000.0) * 100.0);
+    }
+}
+
+// user + system CPU time of this process (Linux only)
+fn cpu_time() -> Option<f64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // skip past the executable name, which may contain spaces
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 2..].split(' ').collect();
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+    Some((utime + stime) / 100.0) // clock ticks, almost always 100/s
+}
@@ -37,6 +37,7 @@ impl ShellAction {
 
 impl IAction for ShellAction {
     fn act(&self) {
+        println!("Running {} action `{}`", &self.shell, &self.command);
         std::process::Command::new(&self.shell)
             .arg("-c")
             .arg(&self.command)
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+const POLL_INTERVAL_MS: u64 = 5; // how long to sleep while waiting for (space for) audio
+
+// Bounded lock-free single-producer single-consumer audio sample queue which works on whole blocks of samples,
+// with the same disconnect behaviour as std::sync::mpsc::channel()
+pub fn audio_buffer(capacity: usize) -> (AudioSender, AudioReceiver) {
+    let (producer, consumer) = RingBuffer::new(capacity).split();
+    let shared = Arc::new(Shared {
+        sender_alive: AtomicBool::new(true),
+        receiver_alive: AtomicBool::new(true),
+        overflow: AtomicUsize::new(0),
+    });
+    (
+        AudioSender {
+            producer,
+            shared: shared.clone(),
+        },
+        AudioReceiver {
+            consumer,
+            shared,
+        },
+    )
+}
+
+struct Shared {
+    sender_alive: AtomicBool,
+    receiver_alive: AtomicBool,
+    overflow: AtomicUsize, // samples dropped because the buffer was full
+}
+
+// Writing half of an audio buffer.
+// There is only one, so it is moved to whatever is capturing audio (e.g. the callback of the active input stream).
+pub struct AudioSender {
+    producer: Producer<i16>,
+    shared: Arc<Shared>,
+}
+
+impl AudioSender {
+    // write samples without waiting, dropping (and counting) whatever doesn't fit
+    // returns false once the receiver is gone
+    pub fn send_block(&mut self, samples: &[i16]) -> bool {
+        let pushed = self.producer.push_slice(samples);
+        if pushed < samples.len() {
+            self.shared.overflow.fetch_add(samples.len() - pushed, Ordering::Relaxed);
+        }
+        self.shared.receiver_alive.load(Ordering::Acquire)
+    }
+
+    // write samples, waiting for space in the buffer instead of dropping any
+    // returns false once the receiver is gone
+    pub fn send_block_blocking(&mut self, mut samples: &[i16]) -> bool {
+        while !samples.is_empty() {
+            if !self.shared.receiver_alive.load(Ordering::Acquire) {
+                return false;
+            }
+            let pushed = self.producer.push_slice(samples);
+            samples = &samples[pushed..];
+            if pushed == 0 {
+                std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            }
+        }
+        self.shared.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for AudioSender {
+    fn drop(&mut self) {
+        self.shared.sender_alive.store(false, Ordering::Release);
+    }
+}
+
+#[derive(Debug)]
+pub struct Disconnected;
+
+// Reading half of an audio buffer
+pub struct AudioReceiver {
+    consumer: Consumer<i16>,
+    shared: Arc<Shared>,
+}
+
+impl AudioReceiver {
+    // wait for samples, then append up to max of them to buffer
+    // errors once the sender is gone and all of its samples have been read
+    pub fn recv_block(&mut self, buffer: &mut Vec<i16>, max: usize) -> Result<usize, Disconnected> {
+        loop {
+            // check before reading, so that samples written right before disconnecting aren't lost
+            let is_disconnected = !self.shared.sender_alive.load(Ordering::Acquire);
+            let count = self.consumer.len().min(max);
+            if count != 0 {
+                let start = buffer.len();
+                buffer.resize(start + count, 0);
+                let popped = self.consumer.pop_slice(&mut buffer[start..]);
+                buffer.truncate(start + popped);
+                return Ok(popped);
+            }
+            if is_disconnected {
+                return Err(Disconnected);
+            }
+            std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    }
+
+    // samples dropped since the last call
+    pub fn take_overflow(&self) -> usize {
+        self.shared.overflow.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl Drop for AudioReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,325 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use crate::audio_buffer::AudioSender;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use crate::config::{InputConfig, DeviceSelector, DeviceFallback};
+use crate::resample::AudioConverter;
+use crate::TARGET_SAMPLE_RATE;
+
+const FILE_BLOCK_SIZE: usize = 1_024; // samples
+const SENDER_RETURN_MS: u64 = 1_000; // how long a dropped input stream may take to hand back the audio sender
+
+pub trait IAudioSource {
+    fn start(&mut self, audio_tx: AudioSender);
+    // called periodically from the main thread
+    fn maintain(&mut self) {}
+    fn describe(&self) -> String;
+}
+
+// Live audio from a cpal input device
+pub struct MicrophoneSource {
+    host: cpal::Host,
+    device: Option<DeviceSelector>,
+    fallback: DeviceFallback,
+    reconnect_interval: Duration,
+    audio_tx: Option<AudioSender>, // while no input stream has it
+    returned_tx: Sender<AudioSender>,
+    returned_rx: Receiver<AudioSender>, // the audio sender of dropped input streams
+    stream: Option<cpal::Stream>,
+    stream_device: String,
+    is_fallback: bool, // using the default device because the configured device is missing
+    last_attempt: Instant,
+    error_tx: Sender<cpal::StreamError>,
+    error_rx: Receiver<cpal::StreamError>,
+}
+
+impl MicrophoneSource {
+    pub fn new(conf: &InputConfig) -> Result<MicrophoneSource, String> {
+        if let InputConfig::Microphone { host, device, fallback, reconnect_ms } = conf {
+            let (error_tx, error_rx) = channel();
+            let (returned_tx, returned_rx) = channel();
+            Ok(MicrophoneSource {
+                // (an unknown host is a config error, unlike a missing device which may be plugged in later)
+                host: find_host(host)?,
+                device: device.clone(),
+                fallback: fallback.clone(),
+                reconnect_interval: Duration::from_millis(*reconnect_ms),
+                audio_tx: None,
+                returned_tx,
+                returned_rx,
+                stream: None,
+                stream_device: String::new(),
+                is_fallback: false,
+                last_attempt: Instant::now(),
+                error_tx,
+                error_rx,
+            })
+        } else {panic!("Non-Microphone config given to MicrophoneSource");}
+    }
+
+    fn build_stream<T: cpal::Sample>(device: &cpal::Device, config: &cpal::StreamConfig, mut audio_tx: StreamSender, error_tx: Sender<cpal::StreamError>) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        let mut converter = AudioConverter::new(config.sample_rate.0, config.channels);
+        let mut samples = Vec::new();
+        device.build_input_stream(config, move |data: &[T], _: &cpal::InputCallbackInfo| {
+            if let Some(tx) = &mut audio_tx.audio_tx {
+                crate::speech::capture_audio(data, &mut converter, &mut samples, tx);
+            }
+        }, move |error| {
+            error_tx.send(error).unwrap_or(()); // handled in maintain()
+        })
+    }
+
+    // find the configured device, or the fallback device if it's missing
+    fn find_device(&self) -> Option<(cpal::Device, bool)> {
+        let host = &self.host;
+        if let Some(selector) = &self.device {
+            if let Some(device) = find_device(host, selector) {
+                return Some((device, false));
+            }
+            if let DeviceFallback::Wait = self.fallback {
+                return None;
+            }
+        }
+        host.default_input_device().map(|d| (d, self.device.is_some()))
+    }
+
+    fn connect(&mut self) -> Result<(), String> {
+        self.last_attempt = Instant::now();
+        let (input_device, is_fallback) = self.find_device()
+            .ok_or_else(|| "configured input device not found".to_owned())?;
+        // prefer 16kHz with as few channels as possible, so that little conversion is needed
+        let mut supported: Option<cpal::SupportedStreamConfig> = None;
+        for sconf in input_device.supported_input_configs().map_err(|e| e.to_string())? {
+            if sconf.min_sample_rate().0 <= TARGET_SAMPLE_RATE
+                && sconf.max_sample_rate().0 >= TARGET_SAMPLE_RATE
+                && supported.as_ref().map(|s| s.channels() > sconf.channels()).unwrap_or(true) {
+                supported = Some(sconf.with_sample_rate(cpal::SampleRate(TARGET_SAMPLE_RATE)));
+            }
+        }
+        // otherwise whatever the device prefers will be converted
+        let supported = match supported {
+            Some(s) => s,
+            None => input_device.default_input_config().map_err(|e| e.to_string())?,
+        };
+        let device_name = input_device.name().unwrap_or("[unknown]".to_owned());
+        println!("Using input device `{}` ({}Hz, {} channel(s), {:?} samples){}",
+                 &device_name, supported.sample_rate().0, supported.channels(), supported.sample_format(),
+                 if is_fallback { " as fallback" } else { "" });
+        let config = supported.config();
+        // (if the stream isn't built after all, the sender is handed back right away)
+        let audio_tx = StreamSender {
+            audio_tx: Some(self.take_sender()?),
+            home: self.returned_tx.clone(),
+        };
+        let error_tx = self.error_tx.clone();
+        let input_stream = match supported.sample_format() {
+            cpal::SampleFormat::F32 => Self::build_stream::<f32>(&input_device, &config, audio_tx, error_tx),
+            cpal::SampleFormat::I16 => Self::build_stream::<i16>(&input_device, &config, audio_tx, error_tx),
+            cpal::SampleFormat::U16 => Self::build_stream::<u16>(&input_device, &config, audio_tx, error_tx),
+        }.map_err(|e| e.to_string())?;
+        input_stream.play().map_err(|e| e.to_string())?;
+        self.stream = Some(input_stream);
+        self.stream_device = device_name;
+        self.is_fallback = is_fallback;
+        Ok(())
+    }
+
+    fn take_sender(&mut self) -> Result<AudioSender, String> {
+        let sender = match self.audio_tx.take() {
+            Some(tx) => Ok(tx),
+            None => self.returned_rx.recv_timeout(Duration::from_millis(SENDER_RETURN_MS)),
+        };
+        sender.map_err(|_| "the previous input stream is still capturing audio".to_owned())
+    }
+}
+
+// Audio sender used by the callback of an input stream,
+// which goes back to the MicrophoneSource once the stream (with its callback) is dropped
+struct StreamSender {
+    audio_tx: Option<AudioSender>,
+    home: Sender<AudioSender>,
+}
+
+impl Drop for StreamSender {
+    fn drop(&mut self) {
+        if let Some(tx) = self.audio_tx.take() {
+            self.home.send(tx).unwrap_or(()); // (the audio sender is dropped too if the MicrophoneSource is gone)
+        }
+    }
+}
+
+impl IAudioSource for MicrophoneSource {
+    fn start(&mut self, audio_tx: AudioSender) {
+        self.audio_tx = Some(audio_tx);
+        if let Err(e) = self.connect() {
+            println!("Failed to open input device ({}), retrying every {}ms", e, self.reconnect_interval.as_millis());
+        }
+    }
+
+    fn maintain(&mut self) {
+        while let Ok(error) = self.error_rx.try_recv() {
+            println!("Stream error {:?}", error);
+            if let cpal::StreamError::DeviceNotAvailable = error {
+                println!("Input device `{}` disconnected", &self.stream_device);
+                self.stream = None;
+            }
+        }
+        if self.last_attempt.elapsed() < self.reconnect_interval {
+            return;
+        }
+        if self.stream.is_some() && self.is_fallback {
+            // switch back once the configured device is available again
+            self.last_attempt = Instant::now();
+            let is_back = self.device.as_ref().map(|d| find_device(&self.host, d).is_some()).unwrap_or(false);
+            if !is_back {
+                return;
+            }
+            self.stream = None;
+        }
+        if self.stream.is_none() && self.connect().is_ok() {
+            println!("Input device `{}` reconnected", &self.stream_device);
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.stream {
+            Some(_) => format!("input device `{}`", &self.stream_device),
+            None => "[disconnected] input device".to_owned(),
+        }
+    }
+}
+
+fn find_host(name: &Option<String>) -> Result<cpal::Host, String> {
+    if let Some(name) = name {
+        for id in cpal::available_hosts() {
+            if id.name().eq_ignore_ascii_case(name) {
+                return cpal::host_from_id(id).map_err(|e| format!("audio host {} is unavailable ({})", name, e));
+            }
+        }
+        return Err(format!("audio host {} is not supported on this platform", name));
+    }
+    Ok(cpal::default_host())
+}
+
+fn find_device(host: &cpal::Host, selector: &DeviceSelector) -> Option<cpal::Device> {
+    let mut devices: Vec<cpal::Device> = host.input_devices().ok()?.collect();
+    match selector {
+        DeviceSelector::Index(index) => {
+            if *index < devices.len() { Some(devices.swap_remove(*index)) } else { None }
+        },
+        DeviceSelector::Name(name) => {
+            // exact name first, then partial name
+            let name_lower = name.to_lowercase();
+            let names: Vec<String> = devices.iter()
+                .map(|d| d.name().unwrap_or_default().to_lowercase())
+                .collect();
+            let index = names.iter().position(|n| n == &name_lower)
+                .or_else(|| names.iter().position(|n| n.contains(&name_lower)))?;
+            Some(devices.swap_remove(index))
+        }
+    }
+}
+
+// Print every audio host and input device, for picking one in the config
+pub fn list_devices() {
+    for id in cpal::available_hosts() {
+        println!("Host `{}`", id.name());
+        let host = match cpal::host_from_id(id) {
+            Ok(h) => h,
+            Err(e) => {
+                println!("  Unavailable: {}", e);
+                continue;
+            }
+        };
+        let default_name = host.default_input_device().and_then(|d| d.name().ok());
+        let devices = match host.input_devices() {
+            Ok(d) => d,
+            Err(e) => {
+                println!("  Failed to list input devices: {}", e);
+                continue;
+            }
+        };
+        for (index, device) in devices.enumerate() {
+            let name = device.name().unwrap_or("[unknown]".to_owned());
+            let is_default = default_name.as_ref() == Some(&name);
+            println!("  {}: `{}`{}", index, &name, if is_default { " (default)" } else { "" });
+            if let Ok(conf) = device.default_input_config() {
+                println!("     {}Hz, {} channel(s), {:?} samples", conf.sample_rate().0, conf.channels(), conf.sample_format());
+            }
+        }
+    }
+}
+
+// Recorded audio from one or more WAV files, played back one after the other
+pub struct WavFileSource {
+    paths: Vec<PathBuf>,
+    padding_ms: u32,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl WavFileSource {
+    pub fn new(conf: &InputConfig) -> WavFileSource {
+        if let InputConfig::File { paths, padding_ms } = conf {
+            WavFileSource {
+                paths: paths.iter().map(PathBuf::from).collect(),
+                padding_ms: *padding_ms,
+                thread: None,
+            }
+        } else {panic!("Non-File config given to WavFileSource");}
+    }
+
+    fn thread(paths: Vec<PathBuf>, padding_ms: u32, mut audio_tx: AudioSender) {
+        let padding_samples = (padding_ms * (TARGET_SAMPLE_RATE / 1_000)) as usize;
+        for path in &paths {
+            let samples = match read_wav(path) {
+                Ok(s) => s,
+                Err(e) => {
+                    println!("Failed to read WAV file {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let padding = vec![0; padding_samples];
+            for block in samples.chunks(FILE_BLOCK_SIZE).chain(padding.chunks(FILE_BLOCK_SIZE)) {
+                if !audio_tx.send_block_blocking(block) {
+                    return; // audio processing has stopped
+                }
+            }
+        }
+        // dropping audio_tx tells the audio processing thread that there is no more input
+    }
+}
+
+impl IAudioSource for WavFileSource {
+    fn start(&mut self, audio_tx: AudioSender) {
+        let paths = self.paths.clone();
+        let padding_ms = self.padding_ms;
+        self.thread = Some(std::thread::spawn(move || {
+            Self::thread(paths, padding_ms, audio_tx);
+        }));
+    }
+
+    fn describe(&self) -> String {
+        format!("{} WAV file(s)", self.paths.len())
+    }
+}
+
+// Decode a WAV file into 16kHz mono samples
+pub fn read_wav(path: &std::path::Path) -> Result<Vec<i16>, hound::Error> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let interleaved: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<f32>, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<Vec<f32>, _>>()?
+        }
+    };
+    let mut converter = AudioConverter::new(spec.sample_rate, spec.channels);
+    let mut result = Vec::with_capacity(interleaved.len());
+    converter.convert(&interleaved, &mut result);
+    Ok(result)
+}
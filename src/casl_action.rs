@@ -22,6 +22,7 @@ impl CASLAction {
 
 impl IAction for CASLAction {
     fn act(&self) {
+        println!("Running CASL action `{}`", &self.operation);
         if let Some(f) = self.func {
             f(&self.parameters);
         }
@@ -36,6 +37,9 @@ fn get_func_by_name(op: &str) -> Option<&'static (dyn Fn(&Vec<String>) -> ())>{
         "debug" => Some(&print_debug),
         "warning" => Some(&print_warn),
         "error" => Some(&print_err),
+        "listen" => Some(&listen),
+        "sleep" => Some(&sleep),
+        "scorer" => Some(&scorer),
         _ => None,
     }
 }
@@ -60,4 +64,21 @@ fn print_warn(params: &Vec<String>) {
 
 fn print_err(params: &Vec<String>) {
     println!("\\/ CASL ERROR MESSAGE \\/\n{}\n/\\CASL ERROR MESSAGE/\\", params.join("\n"));
-}
\ No newline at end of file
+}
+
+fn listen(params: &Vec<String>) {
+    let timeout_ms = params.get(0)
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(crate::wake::DEFAULT_LISTEN_MS);
+    crate::wake::start_listening(std::time::Duration::from_millis(timeout_ms));
+}
+
+fn sleep(_params: &Vec<String>) {
+    crate::wake::stop_listening();
+}
+
+fn scorer(params: &Vec<String>) {
+    let name = params.get(0).map(|p| p.as_str()).unwrap_or(crate::scorer::DEFAULT_SCORER);
+    let utterances = params.get(1).and_then(|p| p.parse().ok());
+    crate::scorer::request_scorer(name, utterances);
+}
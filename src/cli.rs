@@ -0,0 +1,29 @@
+// Command line arguments, which override parts of casl.json
+pub struct CliArgs {
+    pub wav_files: Vec<String>,
+    pub text_mode: bool,
+    pub list_devices: bool,
+    pub benchmark_phrases: Vec<String>, // time the pre-processors and commands with these instead of running
+}
+
+impl CliArgs {
+    pub fn parse() -> CliArgs {
+        let mut result = CliArgs {
+            wav_files: Vec::new(),
+            text_mode: false,
+            list_devices: false,
+            benchmark_phrases: Vec::new(),
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match &arg as &str {
+                "--wav" => result.wav_files.push(args.next().expect("Missing path after --wav")),
+                "--text" => result.text_mode = true,
+                "--list-devices" => result.list_devices = true,
+                "--benchmark" => result.benchmark_phrases.push(args.next().expect("Missing phrase after --benchmark")),
+                _ => println!("Ignoring unknown argument `{}`", arg),
+            }
+        }
+        result
+    }
+}
@@ -1,31 +1,232 @@
-use crate::speech::MetadataResult;
-use crate::config::{Config, CommandConfig};
+use crate::speech::{MetadataResult, CandidateResult};
+use crate::config::{Config, CommandConfig, MatchingMode};
+use crate::pipeline::Pipeline;
 use std::process::{Command, Stdio};
 use std::io::{BufWriter, BufReader, BufRead};
-use crate::command_api::{Payload, Response, CommandAction};
-use regex::{Regex, RegexBuilder};
-use std::path::PathBuf;
+use crate::command_api::{Payload, Alternate, Response, CommandAction};
+use regex::Regex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use serde::Serialize;
 
 const UDP_BUFFER_BYTES: usize = 8192;
+const EXIT_WAIT_MS: u64 = 5_000; // wait for slow commands before exiting
 
-pub trait ICommand {
-    fn run(&self, input: &str);
+pub trait ICommand: Send + Sync {
+    // returns the command's thread, if it runs in the background
+    fn run(&self, payload: &Payload) -> Option<JoinHandle<()>>;
 }
 
-pub fn process_commands(meta_result: &MetadataResult, casl_config: &Config) {
+// Commands run for one phrase
+pub struct Dispatch {
+    pub threads: Vec<JoinHandle<()>>, // commands running in the background
+    pub matched: Vec<MatchedCommand>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct MatchedCommand {
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub precondition: String,
+}
+
+// Eager commands which already ran for partial transcripts of recent utterances,
+// so that they don't run again for their final transcripts
+#[derive(Default)]
+pub struct EagerCommands {
+    utterances: Vec<EagerUtterance>, // oldest first
+}
+
+struct EagerUtterance {
+    // when the utterance was spoken (see MetadataResult)
+    start_ms: u64,
+    end_ms: u64,
+    ran: Vec<usize>, // indexes into Config::commands
+}
+
+pub fn process_commands(meta_result: &MetadataResult, casl_config: &Config, pipeline: &Pipeline) -> Dispatch {
+    run_commands(meta_result, casl_config, pipeline, &mut Vec::new(), false)
+}
+
+// run commands for the final transcript of an utterance, except eager commands which already ran for it
+pub fn process_final_commands(meta_result: &MetadataResult, eager: &mut EagerCommands, casl_config: &Config, pipeline: &Pipeline) -> Dispatch {
+    let same = eager.utterances.iter()
+        .position(|u| meta_result.start_ms <= u.end_ms && meta_result.end_ms >= u.start_ms);
+    let dispatch = match same {
+        Some(index) => run_commands(meta_result, casl_config, pipeline, &mut eager.utterances.remove(index).ran, false),
+        None => process_commands(meta_result, casl_config, pipeline),
+    };
+    // utterances before this one won't get a final transcript anymore
+    eager.utterances.retain(|u| u.end_ms >= meta_result.start_ms);
+    dispatch
+}
+
+// run eager commands for a stable partial transcript
+pub fn process_partial_commands(meta_result: &MetadataResult, eager: &mut EagerCommands, casl_config: &Config, pipeline: &Pipeline) -> Dispatch {
+    let is_new = eager.utterances.last().map(|u| meta_result.start_ms > u.end_ms).unwrap_or(true);
+    if is_new {
+        // a new utterance started after a gap
+        eager.utterances.push(EagerUtterance {
+            start_ms: meta_result.start_ms,
+            end_ms: meta_result.end_ms,
+            ran: Vec::new(),
+        });
+    }
+    let utterance = eager.utterances.last_mut().unwrap();
+    utterance.end_ms = utterance.end_ms.max(meta_result.end_ms);
+    run_commands(meta_result, casl_config, pipeline, &mut utterance.ran, true)
+}
+
+fn run_commands(meta_result: &MetadataResult, casl_config: &Config, pipeline: &Pipeline, eager_ran: &mut Vec<usize>, is_partial: bool) -> Dispatch {
     if casl_config.debug {
-        println!("Heard `{}` (processed into `{}`)", meta_result.phrase_raw, meta_result.phrase);
+        println!("Heard{} `{}` (processed into `{}`)", if is_partial { " (partial)" } else { "" }, meta_result.phrase_raw, meta_result.phrase);
+    }
+    let mut dispatch = Dispatch {
+        threads: Vec::new(),
+        matched: Vec::new(),
+    };
+    if let Some(reason) = rejection(meta_result.confidence, &meta_result.phrase_raw, casl_config.min_confidence, casl_config.min_words) {
+        if casl_config.debug {
+            println!("Rejected `{}` ({})", &meta_result.phrase_raw, reason);
+        }
+        return dispatch;
     }
-    for cmd in &casl_config.commands {
-        if cmd.use_raw() {
-            if cmd.is_match(&meta_result.phrase_raw) {
-                cmd.command().run(&meta_result.phrase_raw);
+    let meta_result = match &casl_config.wake {
+        Some(wake) => match crate::wake::wake_filter(meta_result, wake, casl_config, pipeline) {
+            Some(m) => m,
+            None => return dispatch,
+        },
+        None => meta_result.clone(),
+    };
+    let meta_result = match casl_config.matching {
+        MatchingMode::Top => meta_result,
+        MatchingMode::BestMatch => best_match(meta_result, casl_config, pipeline),
+    };
+    for (index, pipeline_cmd) in pipeline.commands.iter().enumerate() {
+        let cmd = &pipeline_cmd.config;
+        let matched = MatchedCommand {
+            type_name: cmd.type_name().to_owned(),
+            precondition: cmd.precondition().to_owned(),
+        };
+        if eager_ran.contains(&index) {
+            if !is_partial {
+                dispatch.matched.push(matched);
             }
-        } else {
-            if cmd.is_match(&meta_result.phrase) {
-                cmd.command().run(&meta_result.phrase);
+            continue;
+        }
+        if is_partial && !cmd.is_eager() {
+            continue;
+        }
+        let text = if cmd.use_raw() { &meta_result.phrase_raw } else { &meta_result.phrase };
+        if pipeline_cmd.is_match(text) {
+            let (min_confidence, min_words) = cmd.thresholds();
+            if let Some(reason) = rejection(meta_result.confidence, text, min_confidence, min_words) {
+                if casl_config.debug {
+                    println!("Skipped {} command `{}` ({})", cmd.type_name(), cmd.precondition(), reason);
+                }
+                continue;
+            }
+            if casl_config.debug {
+                println!("Matched {} command `{}`", cmd.type_name(), cmd.precondition());
+            }
+            if is_partial {
+                eager_ran.push(index);
+            }
+            dispatch.matched.push(matched);
+            let payload = Payload {
+                text: text.to_owned(),
+                listening: crate::wake::is_listening(),
+                confidence: meta_result.confidence,
+                alternates: meta_result.alternates.iter().map(|a| Alternate {
+                    text: if cmd.use_raw() { a.phrase_raw.clone() } else { a.phrase.clone() },
+                    confidence: a.confidence,
+                }).collect(),
+                start_ms: meta_result.start_ms,
+                end_ms: meta_result.end_ms,
+                words: meta_result.words.clone(),
+                partial: is_partial,
+            };
+            if let Some(thread) = pipeline_cmd.run(&payload) {
+                dispatch.threads.push(thread);
+            }
+        }
+    }
+    dispatch
+}
+
+// why text with this confidence should be ignored, if it should be
+fn rejection(confidence: f64, text: &str, min_confidence: Option<f64>, min_words: usize) -> Option<String> {
+    if let Some(min) = min_confidence {
+        if confidence < min {
+            return Some(format!("confidence {:.2} is below {:.2}", confidence, min));
+        }
+    }
+    let words = text.split_whitespace().count();
+    if words < min_words {
+        return Some(format!("{} word(s), fewer than {}", words, min_words));
+    }
+    None
+}
+
+// the most confident candidate transcript which matches any command, or the top transcript if none match
+// (candidates below the global minimum confidence are never chosen)
+fn best_match(meta_result: MetadataResult, casl_config: &Config, pipeline: &Pipeline) -> MetadataResult {
+    let mut candidates = vec![CandidateResult {
+        phrase_raw: meta_result.phrase_raw.clone(),
+        phrase: meta_result.phrase.clone(),
+        confidence: meta_result.confidence,
+        words: meta_result.words.clone(),
+    }];
+    candidates.extend(meta_result.alternates.iter().cloned());
+    // DeepSpeech sorts candidates already, but other recognizers might not
+    candidates.sort_by(|a, b| b.confidence.partial_cmp(&a.confidence).unwrap_or(std::cmp::Ordering::Equal));
+    let best = candidates.iter().position(|c| {
+        c.confidence >= casl_config.min_confidence.unwrap_or(f64::NEG_INFINITY)
+            && pipeline.commands.iter().any(|cmd| cmd.is_match(if cmd.config.use_raw() { &c.phrase_raw } else { &c.phrase }))
+    });
+    match best {
+        Some(index) if candidates[index].phrase_raw != meta_result.phrase_raw => {
+            let chosen = candidates.remove(index);
+            if casl_config.debug {
+                println!("Using candidate transcript `{}` (confidence {:.2}, top transcript {:.2})",
+                         &chosen.phrase_raw, chosen.confidence, meta_result.confidence);
             }
+            MetadataResult {
+                phrase_raw: chosen.phrase_raw,
+                phrase: chosen.phrase,
+                confidence: chosen.confidence,
+                words: chosen.words,
+                alternates: candidates,
+                ..meta_result
+            }
+        },
+        _ => meta_result,
+    }
+}
+
+// wait (up to timeout in total) for command threads to finish, so their output isn't lost
+// returns the threads which are still running
+pub fn wait_for_commands(threads: Vec<JoinHandle<()>>, timeout: Duration) -> Vec<JoinHandle<()>> {
+    let deadline = Instant::now() + timeout;
+    let mut still_running = Vec::new();
+    for thread in threads {
+        while !thread.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
         }
+        if thread.is_finished() {
+            thread.join().unwrap_or(());
+        } else {
+            still_running.push(thread);
+        }
+    }
+    still_running
+}
+
+// wait for the last commands before exiting, so that they can respond and run their actions
+pub fn wait_before_exit(threads: Vec<JoinHandle<()>>, casl_config: &Config) {
+    let still_running = wait_for_commands(threads, Duration::from_millis(EXIT_WAIT_MS));
+    if casl_config.debug && !still_running.is_empty() {
+        println!("{} command(s) still running, not waiting for them", still_running.len());
     }
 }
 
@@ -50,7 +251,7 @@ impl SocketCommand {
         } else {panic!("Non-Net config given to SocketCommand");}
     }
 
-    fn thread(input: String, src_addr: String, dst_addr: String, src_port: usize, dst_port: usize) {
+    fn thread(payload: Payload, src_addr: String, dst_addr: String, src_port: usize, dst_port: usize) {
         let socket = std::net::UdpSocket::bind(&format!("{}:{}", src_addr, src_port)).unwrap();
         let dst = format!("{}:{}", dst_addr, dst_port);
         let mut buf = [0; UDP_BUFFER_BYTES];
@@ -58,9 +259,6 @@ impl SocketCommand {
         socket.connect(&dst)
             .expect(&format!("Failed to set destination address {}", &dst));
         // send payload
-        let payload = Payload {
-            text: input,
-        };
         if socket.send(serde_json::to_string(&payload)
             .expect("Failed to serialize Payload").as_bytes())
             .is_err() {
@@ -86,15 +284,15 @@ impl SocketCommand {
 }
 
 impl ICommand for SocketCommand {
-    fn run(&self, input: &str) {
-        let input_clone = input.clone().to_owned();
+    fn run(&self, payload: &Payload) -> Option<JoinHandle<()>> {
+        let payload = payload.clone();
         let src_addr = self.src_addr.clone();
         let dst_addr = self.dst_addr.clone();
         let dst_port = self.dst_port;
         let src_port = self.src_port;
-        std::thread::spawn(move || {
-            Self::thread(input_clone, src_addr, dst_addr, src_port, dst_port);
-        });
+        Some(std::thread::spawn(move || {
+            Self::thread(payload, src_addr, dst_addr, src_port, dst_port);
+        }))
     }
 }
 
@@ -113,7 +311,7 @@ impl StdIOCommand {
         } else {panic!("Non-StdIO config given to StdIOCommand");}
     }
 
-    fn thread(input: String, command: String) {
+    fn thread(payload: Payload, command: String) {
         let cmd = Command::new(&command)
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
@@ -121,9 +319,7 @@ impl StdIOCommand {
         let stdin_writer = BufWriter::new(cmd.stdin.unwrap());
         let mut stdout_reader = BufReader::new(cmd.stdout.unwrap());
         // send payload
-        serde_json::to_writer(stdin_writer, &Payload{
-            text: input,
-        }).expect("Failed to serialize Payload");
+        serde_json::to_writer(stdin_writer, &payload).expect("Failed to serialize Payload");
         // receive response
         let mut str_buf = String::new();
         stdout_reader.read_line(&mut str_buf).expect("Failed to read line of stdout");
@@ -140,12 +336,12 @@ impl StdIOCommand {
 }
 
 impl ICommand for StdIOCommand {
-    fn run(&self, input: &str) {
-        let input_clone = input.clone().to_owned();
+    fn run(&self, payload: &Payload) -> Option<JoinHandle<()>> {
+        let payload = payload.clone();
         let command = self.command.clone();
-        std::thread::spawn(move || {
-            Self::thread(input_clone, command);
-        });
+        Some(std::thread::spawn(move || {
+            Self::thread(payload, command);
+        }))
     }
 }
 
@@ -158,23 +354,21 @@ pub struct ShellCommand {
 }
 
 impl ShellCommand {
-    pub fn new(conf: &CommandConfig) -> ShellCommand {
+    pub fn new(conf: &CommandConfig) -> Result<ShellCommand, String> {
         if let CommandConfig::Shell { command, shell, precondition, ..} = conf {
-            ShellCommand {
+            Ok(ShellCommand {
                 command: command.clone(),
                 shell: shell.clone(),
-                precondition: RegexBuilder::new(precondition)
-                    .case_insensitive(true)
-                    .build().expect(&format!("Failed to compile the regex {} for shell command", precondition)),
-            }
+                precondition: crate::pipeline::compile_precondition(precondition)?,
+            })
         } else {panic!("Non-Shell config given to ShellCommand");}
     }
 }
 
 impl ICommand for ShellCommand {
-    fn run(&self, input: &str) {
+    fn run(&self, payload: &Payload) -> Option<JoinHandle<()>> {
         let mut str_buf = String::new();
-        self.precondition.captures(input).unwrap().expand(&self.command, &mut str_buf);
+        self.precondition.captures(&payload.text).unwrap().expand(&self.command, &mut str_buf);
         println!("Running {} command `{}`", &self.shell, &str_buf);
         std::process::Command::new(&self.shell)
             .arg("-c")
@@ -184,44 +378,32 @@ impl ICommand for ShellCommand {
             .stderr(Stdio::null())
             .spawn()
             .expect(&format!("Failed to start {} command {}", &self.shell, &str_buf));
+        None
     }
 }
 
 pub struct RedirectCommand {
     command: Box<dyn ICommand>,
-    path: PathBuf,
 }
 
 impl RedirectCommand {
-    pub fn new(conf: &CommandConfig) -> RedirectCommand {
+    pub fn new(conf: &CommandConfig) -> Result<RedirectCommand, String> {
         if let CommandConfig::Redirect { path, ..} = conf {
-            let json_file = std::fs::File::open(path).unwrap();
+            let json_file = std::fs::File::open(path)
+                .map_err(|e| format!("failed to open {} ({})", path, e))?;
             let json_reader = std::io::BufReader::new(json_file);
             let conf: CommandConfig = serde_json::from_reader(json_reader)
-                .expect(&("Unable to parse JSON file ".to_owned() + path));
-            RedirectCommand {
-                command: conf.command(),
-                path: std::path::PathBuf::from(path),
-            }
-        } else {panic!("Non-Shell config given to ShellCommand");}
+                .map_err(|e| format!("unable to parse JSON file {} ({})", path, e))?;
+            Ok(RedirectCommand {
+                command: conf.command()?,
+            })
+        } else {panic!("Non-Redirect config given to RedirectCommand");}
     }
 }
 
 impl ICommand for RedirectCommand {
-    fn run(&self, input: &str) {
-        self.command.run(input);
-    }
-}
-
-impl Clone for RedirectCommand {
-    fn clone(&self) -> Self {
-        let json_file = std::fs::File::open(self.path.clone()).unwrap();
-        let json_reader = std::io::BufReader::new(json_file);
-        let conf: CommandConfig = serde_json::from_reader(json_reader).unwrap();
-        RedirectCommand {
-            command: conf.command(),
-            path: std::path::PathBuf::from(self.path.clone()),
-        }
+    fn run(&self, payload: &Payload) -> Option<JoinHandle<()>> {
+        self.command.run(payload)
     }
 }
 
@@ -241,10 +423,16 @@ impl AutoActionCommand {
 }
 
 impl ICommand for AutoActionCommand {
-    fn run(&self, _input: &str) {
+    fn run(&self, _payload: &Payload) -> Option<JoinHandle<()>> {
+        if let CommandAction::CASL { .. } = &self.action {
+            // CASL actions are quick, and running them right away means that
+            // they apply before the next utterance (e.g. switching scorers)
+            self.action.action().act();
+            return None;
+        }
         let action = self.action.clone();
-        std::thread::spawn(move || {
+        Some(std::thread::spawn(move || {
             action.action().act();
-        });
+        }))
     }
 }
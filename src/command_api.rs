@@ -6,6 +6,29 @@ use crate::casl_action::CASLAction;
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Payload {
     pub text: String,
+    pub listening: bool, // false while waiting for a wake phrase
+    pub confidence: f64,
+    pub alternates: Vec<Alternate>, // other candidate transcripts, most confident first
+    // when the utterance was spoken, since CASL started listening
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub words: Vec<Word>, // words of the raw text
+    pub partial: bool, // from a partial transcript, for an eager command (the utterance may not be over)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Alternate {
+    pub text: String,
+    pub confidence: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Word {
+    pub text: String,
+    // when the word was spoken, since CASL started listening
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub confidence: f64, // of the transcript it is in (deepspeech doesn't score single words)
 }
 
 // Response JSON which is received from command
@@ -1,45 +1,322 @@
 use serde::{Deserialize, Serialize};
-use crate::preprocessor::{ITextPreprocessor, SimpleMapper, RedirectConfig};
+use crate::preprocessor::{ITextPreprocessor, SimpleMapper, RedirectConfig, RemapMappings, PreprocessorGroup, ConditionalPreprocessor};
+use crate::numbers::NumberNormalizer;
+use crate::spell::Speller;
+use crate::process::ExternalPreprocessor;
+use crate::disfluency::DisfluencyFilter;
+use crate::phonetic::{PhoneticCorrector, PhoneticAlgorithm, VocabularyEntry};
 use crate::command::{ICommand, SocketCommand, StdIOCommand, ShellCommand, RedirectCommand, AutoActionCommand};
 use std::collections::HashMap;
-use regex::{RegexBuilder};
 use crate::command_api::CommandAction;
+use crate::audio_source::{IAudioSource, MicrophoneSource, WavFileSource};
+use crate::vad::VadConfig;
+use crate::wake::WakeConfig;
+use crate::recorder::RecorderConfig;
+use crate::partial::{PartialConfig, PartialDecoder};
+use crate::hot_words::HotWordConfig;
+use crate::recognizer::{ISpeechRecognizer, MockRecognizer, MockTranscript};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
+    #[serde(default)]
     pub model: String,
     pub scorer: Option<String>,
+    // more scorers which CASL actions can switch to, by name
+    #[serde(default)]
+    pub scorers: HashMap<String, String>,
+    #[serde(default)]
+    pub recognizer: RecognizerConfig,
+    pub hot_words: Option<HotWordConfig>,
     pub carryover_buffer_size: usize,
     pub refresh_buffer_threshold: usize,
     pub gap_detection_ms: usize,
+    // most audio to carry over while still talking (default: 3 * refresh_buffer_threshold)
+    pub max_buffer_size: Option<usize>,
+    #[serde(default = "default_candidates")]
+    pub candidates: u32,
+    #[serde(default)]
+    pub matching: MatchingMode,
+    // utterances below these are ignored by all commands
+    pub min_confidence: Option<f64>,
+    #[serde(default)]
+    pub min_words: usize,
+    #[serde(default = "default_audio_buffer_size")]
+    pub audio_buffer_size: usize,
+    pub vad: Option<VadConfig>,
+    pub wake: Option<WakeConfig>,
+    pub recorder: Option<RecorderConfig>,
+    pub partial: Option<PartialConfig>,
+    #[serde(default)]
+    pub input: InputConfig,
     pub preprocessors: Vec<PreprocessorConfig>,
     pub commands: Vec<CommandConfig>,
     pub debug: bool,
 }
 
 impl Config {
+    pub fn recognizer(&self) -> Box<dyn ISpeechRecognizer> {
+        let hot_words = self.hot_words();
+        if self.debug {
+            for (word, boost) in &hot_words {
+                println!("Boosting hot word `{}` by {}", word, boost);
+            }
+        }
+        match &self.recognizer {
+            #[cfg(feature = "deepspeech")]
+            RecognizerConfig::DeepSpeech { } => Box::new(crate::recognizer::DeepSpeechRecognizer::new(&self.model, &self.scorer, &hot_words)),
+            #[cfg(not(feature = "deepspeech"))]
+            RecognizerConfig::DeepSpeech { } => panic!("CASL was built without DeepSpeech support"),
+            RecognizerConfig::Mock { results } => Box::new(MockRecognizer::new(results)),
+        }
+    }
+
+    pub fn max_buffer_size(&self) -> usize {
+        self.max_buffer_size.unwrap_or(self.refresh_buffer_threshold * 3)
+    }
+
+    pub fn hot_words(&self) -> Vec<(String, f32)> {
+        match &self.hot_words {
+            Some(conf) => conf.hot_words(&self.commands),
+            None => Vec::new(),
+        }
+    }
+
+    // partial decoding is needed for eager commands, even if it isn't configured
+    pub fn partial_decoder(&self) -> Option<PartialDecoder> {
+        match &self.partial {
+            Some(conf) => Some(PartialDecoder::new(conf)),
+            None if self.commands.iter().any(|c| c.is_eager()) => Some(PartialDecoder::new(&PartialConfig::default())),
+            None => None,
+        }
+    }
+
+    // smallest config for tests, with a Mock recognizer which returns `results` (a JSON list of MockTranscript)
+    #[cfg(test)]
+    pub fn mock(results: &str) -> Config {
+        serde_json::from_str(&format!(r#"{{
+            "carryover_buffer_size": 8096,
+            "refresh_buffer_threshold": 80000,
+            "gap_detection_ms": 1000,
+            "preprocessors": [],
+            "commands": [],
+            "debug": false,
+            "recognizer": {{"type": "Mock", "results": {}}}
+        }}"#, results)).expect("Invalid test config")
+    }
+}
+
+fn default_audio_buffer_size() -> usize { 64_000 } // 4s at 16kHz
+
+fn default_candidates() -> u32 { 1 }
+
+// Which candidate transcript commands are matched against
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum MatchingMode {
+    #[default]
+    Top, // the most confident transcript
+    BestMatch, // the most confident transcript which matches any command
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum RecognizerConfig {
+    DeepSpeech { }, // uses model and scorer from Config
+    Mock {
+        results: Vec<MockTranscript>,
+    }
+}
+
+impl Default for RecognizerConfig {
+    fn default() -> Self {
+        RecognizerConfig::DeepSpeech { }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum InputConfig {
+    Microphone {
+        #[serde(default)]
+        host: Option<String>,
+        #[serde(default)]
+        device: Option<DeviceSelector>,
+        #[serde(default)]
+        fallback: DeviceFallback,
+        #[serde(default = "default_reconnect_ms")]
+        reconnect_ms: u64,
+    },
+    File {
+        paths: Vec<String>,
+        #[serde(default = "default_padding_ms")]
+        padding_ms: u32,
+    }
+}
+
+pub fn default_padding_ms() -> u32 { 1_000 }
+
+fn default_reconnect_ms() -> u64 { 1_000 }
+
+// Input device by name (or part of a name) or by index, as listed by --list-devices
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum DeviceSelector {
+    Index(usize),
+    Name(String),
+}
+
+// What to do when the configured input device is missing
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum DeviceFallback {
+    #[default]
+    Default, // use the default input device until the configured device is available
+    Wait, // wait for the configured device
+}
+
+impl InputConfig {
+    pub fn source(&self) -> Result<Box<dyn IAudioSource>, String> {
+        match self {
+            InputConfig::Microphone { .. } => Ok(Box::new(MicrophoneSource::new(self)?)),
+            InputConfig::File { .. } => Ok(Box::new(WavFileSource::new(self))),
+        }
+    }
+}
 
+impl Default for InputConfig {
+    fn default() -> Self {
+        InputConfig::Microphone {
+            host: None,
+            device: None,
+            fallback: DeviceFallback::default(),
+            reconnect_ms: default_reconnect_ms(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum PreprocessorConfig {
     Remap {
-        mappings: HashMap<String, String>,
+        mappings: RemapMappings,
     },
     Redirect {
         path: String,
+    },
+    Numbers {
+        // which kinds of spoken numbers to convert into digits
+        #[serde(default = "default_true")]
+        cardinals: bool,
+        #[serde(default = "default_true")]
+        ordinals: bool,
+        #[serde(default = "default_true")]
+        decimals: bool,
+        #[serde(default)]
+        units: bool,
+    },
+    Phonetic {
+        // words and phrases which are often misheard, e.g. names
+        #[serde(default)]
+        vocabulary: Vec<VocabularyEntry>,
+        // file with more of them, one per line
+        #[serde(default)]
+        path: Option<String>,
+        // how close (0 to 1) heard words must be to an entry to be replaced with it
+        #[serde(default = "default_phonetic_threshold")]
+        threshold: f64,
+        // how close (0 to 1) the spelling of heard words must be to an entry, however alike they sound
+        #[serde(default = "default_phonetic_min_spelling")]
+        min_spelling: f64,
+        // longest run of heard words which can be replaced with one entry
+        #[serde(default = "default_phonetic_max_words")]
+        max_words: usize,
+        #[serde(default)]
+        algorithm: PhoneticAlgorithm,
+        // print why each replacement was made
+        #[serde(default)]
+        debug: bool,
+    },
+    Spell {
+        // phrases which start and stop spelling out a word
+        #[serde(default = "default_spell_start")]
+        start: Vec<String>,
+        #[serde(default = "default_spell_stop")]
+        stop: Vec<String>,
+        // more spoken words and what to write for them, e.g. "at": "@"
+        #[serde(default)]
+        words: HashMap<String, String>,
+    },
+    Process {
+        // program which is sent every phrase and sends back the text to use instead
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default = "default_process_timeout_ms")]
+        timeout_ms: u64,
+    },
+    Group {
+        preprocessors: Vec<PreprocessorConfig>,
+    },
+    When {
+        // (case insensitive) regex which the text must match, and one which it must not match
+        #[serde(default)]
+        precondition: Option<String>,
+        #[serde(default)]
+        unless: Option<String>,
+        preprocessor: Box<PreprocessorConfig>,
+    },
+    Disfluency {
+        // words and phrases to remove
+        #[serde(default = "default_disfluency_fillers")]
+        fillers: Vec<String>,
+        // phrases which replace what was just said with what is said after them
+        #[serde(default = "default_disfluency_corrections")]
+        corrections: Vec<String>,
+        // collapse words said twice in a row
+        #[serde(default = "default_true")]
+        repetitions: bool,
     }
 }
 
+fn default_true() -> bool { true }
+fn default_spell_start() -> Vec<String> { vec!["spell".to_owned()] }
+fn default_spell_stop() -> Vec<String> { vec!["stop".to_owned()] }
+fn default_process_timeout_ms() -> u64 { 500 }
+fn default_disfluency_fillers() -> Vec<String> {
+    ["um", "umm", "uh", "uhh", "uhm", "er", "erm", "ah", "hmm", "mm"].iter().map(|f| (*f).to_owned()).collect()
+}
+fn default_disfluency_corrections() -> Vec<String> { vec!["i mean".to_owned(), "no wait".to_owned()] }
+fn default_phonetic_threshold() -> f64 { 0.8 }
+fn default_phonetic_min_spelling() -> f64 { 0.7 }
+fn default_phonetic_max_words() -> usize { 3 }
+
 impl PreprocessorConfig {
-    pub fn preprocessor(&self) -> Box<dyn ITextPreprocessor> {
+    pub fn preprocessor(&self) -> Result<Box<dyn ITextPreprocessor>, String> {
         match self {
             PreprocessorConfig::Remap { mappings } => {
-                return Box::new(SimpleMapper::from_mappings(mappings));
+                return Ok(Box::new(SimpleMapper::from_mappings(mappings)?));
             },
             PreprocessorConfig::Redirect { path } => {
-                return Box::new(RedirectConfig::from_path(std::path::Path::new(path)));
+                return Ok(Box::new(RedirectConfig::from_path(std::path::Path::new(path))?));
+            },
+            PreprocessorConfig::Numbers { .. } => {
+                return Ok(Box::new(NumberNormalizer::new(self)));
+            },
+            PreprocessorConfig::Phonetic { .. } => {
+                return Ok(Box::new(PhoneticCorrector::new(self)?));
+            },
+            PreprocessorConfig::Spell { .. } => {
+                return Ok(Box::new(Speller::new(self)));
+            },
+            PreprocessorConfig::Process { .. } => {
+                return Ok(Box::new(ExternalPreprocessor::new(self)?));
+            },
+            PreprocessorConfig::Group { preprocessors } => {
+                return Ok(Box::new(PreprocessorGroup::from_configs(preprocessors)?));
+            },
+            PreprocessorConfig::When { .. } => {
+                return Ok(Box::new(ConditionalPreprocessor::new(self)?));
+            },
+            PreprocessorConfig::Disfluency { .. } => {
+                return Ok(Box::new(DisfluencyFilter::new(self)));
             }
         }
     }
@@ -53,6 +330,44 @@ impl Clone for PreprocessorConfig {
             },
             PreprocessorConfig::Redirect { path } => PreprocessorConfig::Redirect {
                 path: path.clone(),
+            },
+            PreprocessorConfig::Numbers { cardinals, ordinals, decimals, units } => PreprocessorConfig::Numbers {
+                cardinals: *cardinals,
+                ordinals: *ordinals,
+                decimals: *decimals,
+                units: *units,
+            },
+            PreprocessorConfig::Phonetic { vocabulary, path, threshold, min_spelling, max_words, algorithm, debug } => PreprocessorConfig::Phonetic {
+                vocabulary: vocabulary.clone(),
+                path: path.clone(),
+                threshold: *threshold,
+                min_spelling: *min_spelling,
+                max_words: *max_words,
+                algorithm: algorithm.clone(),
+                debug: *debug,
+            },
+            PreprocessorConfig::Spell { start, stop, words } => PreprocessorConfig::Spell {
+                start: start.clone(),
+                stop: stop.clone(),
+                words: words.clone(),
+            },
+            PreprocessorConfig::Process { command, args, timeout_ms } => PreprocessorConfig::Process {
+                command: command.clone(),
+                args: args.clone(),
+                timeout_ms: *timeout_ms,
+            },
+            PreprocessorConfig::Group { preprocessors } => PreprocessorConfig::Group {
+                preprocessors: preprocessors.clone(),
+            },
+            PreprocessorConfig::When { precondition, unless, preprocessor } => PreprocessorConfig::When {
+                precondition: precondition.clone(),
+                unless: unless.clone(),
+                preprocessor: preprocessor.clone(),
+            },
+            PreprocessorConfig::Disfluency { fillers, corrections, repetitions } => PreprocessorConfig::Disfluency {
+                fillers: fillers.clone(),
+                corrections: corrections.clone(),
+                repetitions: *repetitions,
             }
         }
     }
@@ -64,6 +379,12 @@ pub enum CommandConfig {
     Net {
         precondition: String,
         use_raw_text: bool,
+        #[serde(default)]
+        eager: bool,
+        #[serde(default)]
+        min_confidence: Option<f64>,
+        #[serde(default)]
+        min_words: usize,
         dst_port: usize,
         src_port: usize,
         src_addr: Option<String>,
@@ -73,33 +394,57 @@ pub enum CommandConfig {
         precondition: String,
         command: String,
         use_raw_text: bool,
+        #[serde(default)]
+        eager: bool,
+        #[serde(default)]
+        min_confidence: Option<f64>,
+        #[serde(default)]
+        min_words: usize,
     },
     Shell { /* !! Does not use API !! */
         precondition: String,
         command: String,
         shell: String,
         use_raw_text: bool,
+        #[serde(default)]
+        eager: bool,
+        #[serde(default)]
+        min_confidence: Option<f64>,
+        #[serde(default)]
+        min_words: usize,
     },
     Action {
         precondition: String,
         use_raw_text: bool,
+        #[serde(default)]
+        eager: bool,
+        #[serde(default)]
+        min_confidence: Option<f64>,
+        #[serde(default)]
+        min_words: usize,
         action: CommandAction,
     },
     Redirect {
         precondition: String,
         use_raw_text: bool,
+        #[serde(default)]
+        eager: bool,
+        #[serde(default)]
+        min_confidence: Option<f64>,
+        #[serde(default)]
+        min_words: usize,
         path: String,
     }
 }
 
 impl CommandConfig {
-    pub fn command(&self) -> Box<dyn ICommand> {
+    pub fn command(&self) -> Result<Box<dyn ICommand>, String> {
         match self {
-            CommandConfig::Net { .. } => Box::new(SocketCommand::new(self)),
-            CommandConfig::StdIO { .. } => Box::new(StdIOCommand::new(self)),
-            CommandConfig::Shell { .. } => Box::new(ShellCommand::new(self)),
-            CommandConfig::Redirect { .. } => Box::new(RedirectCommand::new(self)),
-            CommandConfig::Action { .. } => Box::new(AutoActionCommand::new(self)),
+            CommandConfig::Net { .. } => Ok(Box::new(SocketCommand::new(self))),
+            CommandConfig::StdIO { .. } => Ok(Box::new(StdIOCommand::new(self))),
+            CommandConfig::Shell { .. } => Ok(Box::new(ShellCommand::new(self)?)),
+            CommandConfig::Redirect { .. } => Ok(Box::new(RedirectCommand::new(self)?)),
+            CommandConfig::Action { .. } => Ok(Box::new(AutoActionCommand::new(self))),
         }
     }
 
@@ -113,18 +458,45 @@ impl CommandConfig {
         }
     }
 
-    pub fn is_match(&self, text: &str) -> bool {
-        let precondition = match self {
+    // run as soon as a stable partial transcript matches, instead of waiting for the final transcript
+    pub fn is_eager(&self) -> bool {
+        match self {
+            CommandConfig::Net { eager, .. } => *eager,
+            CommandConfig::StdIO { eager, .. } => *eager,
+            CommandConfig::Shell { eager, .. } => *eager,
+            CommandConfig::Redirect { eager, .. } => *eager,
+            CommandConfig::Action { eager, .. } => *eager,
+        }
+    }
+
+    // (minimum confidence, minimum word count) of text the command accepts
+    pub fn thresholds(&self) -> (Option<f64>, usize) {
+        match self {
+            CommandConfig::Net { min_confidence, min_words, .. } => (*min_confidence, *min_words),
+            CommandConfig::StdIO { min_confidence, min_words, .. } => (*min_confidence, *min_words),
+            CommandConfig::Shell { min_confidence, min_words, .. } => (*min_confidence, *min_words),
+            CommandConfig::Redirect { min_confidence, min_words, .. } => (*min_confidence, *min_words),
+            CommandConfig::Action { min_confidence, min_words, .. } => (*min_confidence, *min_words),
+        }
+    }
+
+    pub fn precondition(&self) -> &str {
+        match self {
             CommandConfig::Net { precondition, .. } => precondition,
             CommandConfig::StdIO { precondition, .. } => precondition,
             CommandConfig::Shell { precondition, .. } => precondition,
             CommandConfig::Redirect { precondition, .. } => precondition,
             CommandConfig::Action { precondition, .. } => precondition,
-        };
-        let re = RegexBuilder::new(precondition)
-            .case_insensitive(true)
-            .build()
-            .expect(&format!("Failed to compile the regex {} for CommandConfig", precondition));
-        re.is_match(text)
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            CommandConfig::Net { .. } => "Net",
+            CommandConfig::StdIO { .. } => "StdIO",
+            CommandConfig::Shell { .. } => "Shell",
+            CommandConfig::Redirect { .. } => "Redirect",
+            CommandConfig::Action { .. } => "Action",
+        }
     }
 }
@@ -0,0 +1,128 @@
+use crate::config::PreprocessorConfig;
+use crate::preprocessor::{ITextPreprocessor, split_phrases, longest_match};
+use crate::numbers::is_number_word;
+
+const MAX_REPEATED_WORDS: usize = 3; // "open the open the" is a repetition, longer runs are probably meant
+
+// Removes hesitations from speech, e.g. "um, open, uh, the the browser" -> "open the browser"
+// and "open firefox i mean chrome" -> "open chrome"
+pub struct DisfluencyFilter {
+    fillers: Vec<Vec<String>>,
+    corrections: Vec<Vec<String>>,
+    repetitions: bool,
+}
+
+impl DisfluencyFilter {
+    pub fn new(conf: &PreprocessorConfig) -> DisfluencyFilter {
+        if let PreprocessorConfig::Disfluency { fillers, corrections, repetitions } = conf {
+            DisfluencyFilter {
+                fillers: split_phrases(fillers),
+                corrections: split_phrases(corrections),
+                repetitions: *repetitions,
+            }
+        } else {panic!("Non-Disfluency config given to DisfluencyFilter");}
+    }
+}
+
+impl ITextPreprocessor for DisfluencyFilter {
+    fn process(&self, input: &str) -> String {
+        // (original, lowercase) of each word, without the commas which mark pauses
+        let words: Vec<(&str, String)> = input.split_whitespace()
+            .map(strip_pauses)
+            .filter(|w| !w.is_empty())
+            .map(|w| (w, w.to_lowercase()))
+            .collect();
+        let lower: Vec<String> = words.iter().map(|(_, l)| l.clone()).collect();
+        let mut result: Vec<usize> = Vec::with_capacity(words.len()); // indices of the words kept
+        let mut i = 0;
+        while i < words.len() {
+            if let Some(n) = longest_match(&self.fillers, &lower[i..]) {
+                i += n;
+                continue;
+            }
+            if let Some(n) = longest_match(&self.corrections, &lower[i..]) {
+                i += n;
+                // the correction replaces what was said before it from the same word, e.g. "open the browser no wait the terminal",
+                // or else just the last word, e.g. "set volume to fifty i mean sixty"
+                // (without anything after it, only the correction phrase is removed)
+                let repair = match lower[i..].iter().find(|w| longest_match(&self.fillers, &[(*w).clone()]).is_none()) {
+                    Some(r) => r,
+                    None => continue,
+                };
+                match result.iter().rposition(|&k| &lower[k] == repair) {
+                    Some(from) => result.truncate(from),
+                    None => { result.pop(); },
+                }
+                continue;
+            }
+            result.push(i);
+            i += 1;
+            if self.repetitions {
+                // "open open the" -> "open the" (the repeated words are dropped as soon as they're complete),
+                // but numbers and letters are often repeated on purpose, like "nine one one" and "a a b"
+                for n in 1..=MAX_REPEATED_WORDS {
+                    let len = result.len();
+                    if len >= 2 * n
+                        && (0..n).all(|k| lower[result[len - 2 * n + k]] == lower[result[len - n + k]])
+                        && result[len - n..].iter().any(|&k| !is_countable(&lower[k])) {
+                        result.truncate(len - n);
+                        break;
+                    }
+                }
+            }
+        }
+        result.iter()
+            .map(|&k| words[k].0)
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
+// number words, written numbers (like "9", "3.5" and "21st") and single letters
+fn is_countable(word: &str) -> bool {
+    word.chars().count() == 1 || word.starts_with(|c: char| c.is_ascii_digit()) || is_number_word(word)
+}
+
+// "um," -> "um" and "well..." -> "well"
+fn strip_pauses(word: &str) -> &str {
+    let mut word = word.trim_matches(|c| c == ',' || c == '…');
+    while let Some(w) = word.strip_suffix("...") {
+        word = w.trim_end_matches(',');
+    }
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(text: &str) -> String {
+        let conf: PreprocessorConfig = serde_json::from_str(r#"{"type": "Disfluency"}"#).expect("Invalid test config");
+        DisfluencyFilter::new(&conf).process(text)
+    }
+
+    #[test]
+    fn removes_fillers_and_repetitions() {
+        assert_eq!(filter("um, open, uh, the the   browser"), "open the browser");
+        assert_eq!(filter("open the open the browser"), "open the browser");
+    }
+
+    #[test]
+    fn keeps_repeated_numbers_and_letters() {
+        assert_eq!(filter("call nine one one one"), "call nine one one one");
+        assert_eq!(filter("nine one one"), "nine one one");
+        assert_eq!(filter("call 9 1 1"), "call 9 1 1");
+        assert_eq!(filter("spell a a b"), "spell a a b");
+        // (unless something else is repeated with them)
+        assert_eq!(filter("call nine call nine one one"), "call nine one one");
+    }
+
+    #[test]
+    fn applies_corrections() {
+        assert_eq!(filter("open firefox i mean chrome"), "open chrome");
+        assert_eq!(filter("open the browser no wait the terminal"), "open the terminal");
+        // nothing to correct with
+        assert_eq!(filter("open the browser i mean um"), "open the browser");
+        assert_eq!(filter("open the browser no wait"), "open the browser");
+    }
+}
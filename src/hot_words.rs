@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::config::CommandConfig;
+
+const MIN_PRECONDITION_WORD_LETTERS: usize = 3;
+// common words which would be heard everywhere if they were boosted
+const STOP_WORDS: [&str; 14] = [
+    "the", "and", "for", "from", "with", "this", "that", "you", "your", "are", "not", "off", "out", "down",
+];
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HotWordConfig {
+    // word -> boost (positive makes the word more likely, negative less likely)
+    #[serde(default)]
+    pub words: HashMap<String, f32>,
+    // also boost the literal words in every command precondition
+    #[serde(default)]
+    pub from_preconditions: bool,
+    #[serde(default = "default_precondition_boost")]
+    pub precondition_boost: f32,
+}
+
+fn default_precondition_boost() -> f32 { 5.0 }
+
+impl HotWordConfig {
+    // every word to boost, with its boost (configured words take priority over derived words)
+    pub fn hot_words(&self, commands: &[CommandConfig]) -> Vec<(String, f32)> {
+        let mut hot_words: HashMap<String, f32> = HashMap::new();
+        if self.from_preconditions {
+            for cmd in commands {
+                for word in precondition_words(cmd.precondition()) {
+                    if word.chars().count() >= MIN_PRECONDITION_WORD_LETTERS && !STOP_WORDS.contains(&word.as_str()) {
+                        hot_words.insert(word, self.precondition_boost);
+                    }
+                }
+            }
+        }
+        for (word, boost) in &self.words {
+            hot_words.insert(word.to_lowercase(), *boost);
+        }
+        let mut hot_words: Vec<(String, f32)> = hot_words.into_iter().collect();
+        hot_words.sort_by(|a, b| a.0.cmp(&b.0));
+        hot_words
+    }
+}
+
+// Literal words in a precondition regex, e.g.
+// `^starts?\ssteam(\s|$)` -> ["start", "steam"] and `(turn|switch) on` -> ["turn", "switch", "on"]
+// This only understands simple regexes; anything unusual is skipped rather than guessed.
+pub fn precondition_words(precondition: &str) -> Vec<String> {
+    let chars: Vec<char> = precondition.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut is_literal = true; // false once part of the word turns out to be optional or repeated
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_alphabetic() || c == '\'' {
+            if matches!(chars.get(i + 1), Some('?') | Some('*') | Some('{')) {
+                // an optional last letter (like a plural s) still leaves a word, otherwise the word is unknown
+                let mut next = i + 2;
+                if chars[i + 1] == '{' {
+                    while next < chars.len() && chars[next - 1] != '}' {
+                        next += 1;
+                    }
+                }
+                if chars.get(next).map(|c| c.is_alphabetic()).unwrap_or(false) {
+                    is_literal = false;
+                } else {
+                    finish_word(&mut word, is_literal, &mut words);
+                    is_literal = false;
+                }
+            } else {
+                word.extend(c.to_lowercase());
+            }
+            i += 1;
+            continue;
+        }
+        finish_word(&mut word, is_literal, &mut words);
+        is_literal = true;
+        match c {
+            '\\' => i += 2, // escape sequence like \s or \b
+            '[' => {
+                // skip character classes
+                while i < chars.len() && chars[i] != ']' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                i += 1;
+            },
+            '(' if chars.get(i + 1) == Some(&'?') => {
+                // skip group flags and names, like (?i) or (?P<name>
+                while i < chars.len() && !matches!(chars[i], ')' | ':' | '>') {
+                    i += 1;
+                }
+                i += 1;
+            },
+            '{' => {
+                while i < chars.len() && chars[i] != '}' {
+                    i += 1;
+                }
+                i += 1;
+            },
+            _ => i += 1,
+        }
+    }
+    finish_word(&mut word, is_literal, &mut words);
+    words
+}
+
+fn finish_word(word: &mut String, is_literal: bool, words: &mut Vec<String>) {
+    if is_literal && word.chars().count() > 1 && !words.contains(word) {
+        words.push(word.clone());
+    }
+    word.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_literal_words() {
+        assert_eq!(precondition_words(r"^starts?\ssteam(\s|$)"), vec!["start", "steam"]);
+        assert_eq!(precondition_words("(turn|switch) on"), vec!["turn", "switch", "on"]);
+        assert_eq!(precondition_words(r"^set volume to \d+$"), vec!["set", "volume", "to"]);
+        assert_eq!(precondition_words("^(turn|switch) (on|off) the lights?$"), vec!["turn", "switch", "on", "off", "the", "light"]);
+    }
+
+    #[test]
+    fn skips_what_isnt_a_literal_word() {
+        // optional and repeated letters in the middle of a word
+        assert_eq!(precondition_words("colou?r mode"), vec!["mode"]);
+        assert_eq!(precondition_words("x{2} ray"), vec!["ray"]);
+        // character classes, group flags and names, and single letters
+        assert_eq!(precondition_words("[a-z]+ mode"), vec!["mode"]);
+        assert_eq!(precondition_words(r"^(?i)open (?P<app>\w+)$"), vec!["open"]);
+        assert_eq!(precondition_words("spell a b"), vec!["spell"]);
+    }
+
+    #[test]
+    fn boosts_precondition_words_except_common_ones() {
+        let conf: HotWordConfig = serde_json::from_str(r#"{"from_preconditions": true, "words": {"Turn": 2}}"#)
+            .expect("Invalid test config");
+        let commands: Vec<CommandConfig> = serde_json::from_str(r#"[{
+            "type": "Shell",
+            "precondition": "^(turn|switch) (on|off) the lights?$",
+            "use_raw_text": false,
+            "command": "lights",
+            "shell": "sh"
+        }]"#).expect("Invalid test commands");
+        assert_eq!(conf.hot_words(&commands), vec![
+            ("light".to_owned(), 5.0),
+            ("switch".to_owned(), 5.0),
+            ("turn".to_owned(), 2.0),
+        ]);
+    }
+}
@@ -6,50 +6,112 @@ mod preprocessor;
 mod command_api;
 mod action;
 mod casl_action;
+mod audio_source;
+mod cli;
+mod recognizer;
+mod text_input;
+mod resample;
+mod audio_buffer;
+mod vad;
+mod wake;
+mod recorder;
+mod partial;
+mod hot_words;
+mod scorer;
+mod segmenter;
+mod pipeline;
+mod numbers;
+mod phonetic;
+mod spell;
+mod process;
+mod disfluency;
 
-use std::sync::mpsc::{channel};
-use cpal::traits::{DeviceTrait, HostTrait};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
 
 const TARGET_SAMPLE_RATE: u32 = 16_000;
+const MAINTENANCE_INTERVAL_MS: u64 = 250;
 
 fn main() -> Result<(), ()> {
     println!("CASL, hello!");
 
     // init
+    let args = cli::CliArgs::parse();
+    if args.list_devices {
+        audio_source::list_devices();
+        return Ok(());
+    }
     let json_file = std::fs::File::open("casl.json").unwrap();
     let json_reader = std::io::BufReader::new(json_file);
-    let casl_config: config::Config = serde_json::from_reader(json_reader).unwrap();
+    let mut casl_config: config::Config = serde_json::from_reader(json_reader).unwrap();
+    if !args.wav_files.is_empty() {
+        let padding_ms = match &casl_config.input {
+            config::InputConfig::File { padding_ms, .. } => *padding_ms,
+            _ => config::default_padding_ms(),
+        };
+        casl_config.input = config::InputConfig::File {
+            paths: args.wav_files.clone(),
+            padding_ms,
+        };
+    }
+    if args.text_mode {
+        // show matched commands for every typed phrase
+        casl_config.debug = true;
+    }
     if casl_config.debug {
         println!("Loaded config with debug messages enabled");
     }
+    // build the pre-processors and commands once, so that config errors show up now
+    let pipeline = match pipeline::Pipeline::new(&casl_config) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Invalid config in casl.json, {}", e);
+            return Err(());
+        }
+    };
+    if !args.benchmark_phrases.is_empty() {
+        pipeline::benchmark(&casl_config, &args.benchmark_phrases);
+        return Ok(());
+    }
+    if casl_config.wake.is_some() {
+        wake::enable_gate();
+    }
+
+    if args.text_mode {
+        // no audio, so no need to load the model or open an audio device
+        println!("CASL, ready! ({} pre-processors, {} commands, text mode)", casl_config.preprocessors.len(), casl_config.commands.len());
+        text_input::process_text_loop(&casl_config, &pipeline);
+        println!("CASL, goodbye!");
+        return Ok(());
+    }
+
+    let mut audio_source = match casl_config.input.source() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Invalid config in casl.json, input: {}", e);
+            return Err(());
+        }
+    };
 
     // start audio processing thread
+    let (stop_tx, stop_rx) = channel();
     let (audio_thread_cntrl_tx, audio_thread_cntrl_rx) = channel();
-    let (audio_thread_sample_tx, audio_thread_sample_rx) = channel();
+    let (audio_thread_sample_tx, audio_thread_sample_rx) = audio_buffer::audio_buffer(casl_config.audio_buffer_size);
     let audio_conf = casl_config.clone();
+    let audio_stop_tx = stop_tx.clone();
     let audio_thread = std::thread::spawn(move || {
-        speech::process_audio_loop(audio_thread_cntrl_rx, audio_thread_sample_rx, &audio_conf);
+        speech::process_audio_loop(audio_thread_cntrl_rx, audio_thread_sample_rx, &audio_conf, &pipeline);
+        audio_stop_tx.send(true).unwrap_or(()); // input ended
     });
     if casl_config.debug {
         println!("Started audio processing thread");
     }
 
-    // start audio capturing thread
-    let host = cpal::default_host();
-    let input_device = host.default_input_device().expect("No input device found");
-    let mut config: Option<cpal::StreamConfig> = None;
-    for sconf in input_device.supported_input_configs().unwrap() {
-        if sconf.min_sample_rate().0 <= TARGET_SAMPLE_RATE
-            && sconf.max_sample_rate().0 >= TARGET_SAMPLE_RATE {
-            let built_config = sconf.with_sample_rate(cpal::SampleRate(TARGET_SAMPLE_RATE)).config();
-            //println!("Using config with sample rate {:?} and {} channels", built_config.sample_rate, built_config.channels as u16);
-            config = Some(built_config);
-            break;
-        }
+    // start audio capturing
+    audio_source.start(audio_thread_sample_tx);
+    if casl_config.debug {
+        println!("Capturing audio from {}", audio_source.describe());
     }
-    let input_stream = input_device.build_input_stream(&config.expect("Input device does not support 16kHz mode"), move |a, b| {
-        speech::capture_audio(a, b, audio_thread_sample_tx.clone());
-    }, speech::capture_error).unwrap();
 
     // ready (debug info)
     if casl_config.debug {
@@ -58,20 +120,23 @@ fn main() -> Result<(), ()> {
     }
     println!("CASL, ready! ({} pre-processors, {} commands)", casl_config.preprocessors.len(), casl_config.commands.len());
 
-    // wait for interrupt signal
-    let (stop_tx, stop_rx) = channel();
+    // wait for interrupt signal (or end of input)
     ctrlc::set_handler( move || {
-        audio_thread_cntrl_tx.send(true).unwrap();
-        stop_tx.send(true).unwrap();
+        audio_thread_cntrl_tx.send(true).unwrap_or(());
+        stop_tx.send(true).unwrap_or(());
     }).unwrap();
-    let mut is_exiting = stop_rx.recv();
-    while is_exiting.is_ok() && !is_exiting.unwrap() {
-        is_exiting = stop_rx.recv();
+    let mut is_exiting = false;
+    while !is_exiting {
+        match stop_rx.recv_timeout(Duration::from_millis(MAINTENANCE_INTERVAL_MS)) {
+            Ok(stop) => is_exiting = stop,
+            Err(RecvTimeoutError::Timeout) => audio_source.maintain(),
+            Err(RecvTimeoutError::Disconnected) => is_exiting = true,
+        }
     }
 
     // cleanup
     println!("CASL, goodbye!");
-    drop(input_stream);
+    drop(audio_source);
     audio_thread.join().unwrap();
     Ok(())
 }
@@ -0,0 +1,240 @@
+use crate::config::PreprocessorConfig;
+use crate::preprocessor::ITextPreprocessor;
+
+const SMALL: [&str; 20] = ["zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine",
+    "ten", "eleven", "twelve", "thirteen", "fourteen", "fifteen", "sixteen", "seventeen", "eighteen", "nineteen"];
+const TENS: [&str; 8] = ["twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety"];
+const SCALES: [(&str, u64); 3] = [("thousand", 1_000), ("million", 1_000_000), ("billion", 1_000_000_000)];
+
+const SMALL_ORDINALS: [&str; 20] = ["zeroth", "first", "second", "third", "fourth", "fifth", "sixth", "seventh", "eighth", "ninth",
+    "tenth", "eleventh", "twelfth", "thirteenth", "fourteenth", "fifteenth", "sixteenth", "seventeenth", "eighteenth", "nineteenth"];
+const TENS_ORDINALS: [&str; 8] = ["twentieth", "thirtieth", "fortieth", "fiftieth", "sixtieth", "seventieth", "eightieth", "ninetieth"];
+const SCALE_ORDINALS: [(&str, u64); 3] = [("thousandth", 1_000), ("millionth", 1_000_000), ("billionth", 1_000_000_000)];
+
+// (spoken unit words, written unit) -- a written unit starting with a space is written after a space
+const UNITS: [(&[&str], &str); 16] = [
+    (&["percent"], "%"),
+    (&["per", "cent"], "%"),
+    (&["degrees"], "°"),
+    (&["degree"], "°"),
+    (&["kilometers"], " km"),
+    (&["kilometres"], " km"),
+    (&["meters"], " m"),
+    (&["metres"], " m"),
+    (&["centimeters"], " cm"),
+    (&["centimetres"], " cm"),
+    (&["kilograms"], " kg"),
+    (&["grams"], " g"),
+    (&["miles"], " mi"),
+    (&["pounds"], " lb"),
+    (&["liters"], " l"),
+    (&["litres"], " l"),
+];
+
+// Converts spoken numbers into digits, e.g. "one hundred and five" -> "105" and "twenty first" -> "21st"
+// Only whole words are converted, so "someone" stays "someone".
+pub struct NumberNormalizer {
+    cardinals: bool,
+    ordinals: bool,
+    decimals: bool,
+    units: bool,
+}
+
+impl NumberNormalizer {
+    pub fn new(conf: &PreprocessorConfig) -> NumberNormalizer {
+        if let PreprocessorConfig::Numbers { cardinals, ordinals, decimals, units } = conf {
+            NumberNormalizer {
+                cardinals: *cardinals,
+                ordinals: *ordinals,
+                decimals: *decimals,
+                units: *units,
+            }
+        } else {panic!("Non-Numbers config given to NumberNormalizer");}
+    }
+
+    // the written form of the number at the start of words, and how many words it replaces
+    fn parse(&self, words: &[String]) -> Option<(String, usize)> {
+        let (value, is_ordinal, mut used) = parse_number(words, self.ordinals)?;
+        if is_ordinal {
+            return Some((format!("{}{}", value, ordinal_suffix(value)), used));
+        }
+        if !self.cardinals {
+            return None;
+        }
+        let mut written = value.to_string();
+        if self.decimals && words.get(used).map(|w| w == "point").unwrap_or(false) {
+            let digits: String = words[used + 1..].iter()
+                .map_while(|w| digit(w))
+                .collect();
+            if !digits.is_empty() {
+                used += 1 + digits.len();
+                written = format!("{}.{}", written, digits);
+            }
+        }
+        if self.units {
+            for (spoken, unit) in &UNITS {
+                let is_unit = words.len() >= used + spoken.len()
+                    && spoken.iter().zip(&words[used..]).all(|(s, w)| s == w);
+                if is_unit {
+                    used += spoken.len();
+                    written.push_str(unit);
+                    break;
+                }
+            }
+        }
+        Some((written, used))
+    }
+}
+
+impl ITextPreprocessor for NumberNormalizer {
+    fn process(&self, input: &str) -> String {
+        let words: Vec<String> = input.split_whitespace().map(|w| w.to_lowercase()).collect();
+        let originals: Vec<&str> = input.split_whitespace().collect();
+        let mut result: Vec<String> = Vec::with_capacity(words.len());
+        let mut i = 0;
+        while i < words.len() {
+            match self.parse(&words[i..]) {
+                Some((written, used)) => {
+                    result.push(written);
+                    i += used;
+                },
+                None => {
+                    result.push(originals[i].to_owned());
+                    i += 1;
+                }
+            }
+        }
+        result.join(" ")
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Part {
+    Start,
+    Small, // 0 to 9
+    Teen, // 10 to 19
+    Tens,
+    Hundred,
+    Scale,
+    And,
+}
+
+// (value, whether it ends with an ordinal word, words used) of the number at the start of words
+fn parse_number(words: &[String], ordinals: bool) -> Option<(u64, bool, usize)> {
+    let mut total: u64 = 0;
+    let mut current: u64 = 0;
+    let mut last = Part::Start;
+    let mut last_scale = u64::MAX;
+    let mut used = 0;
+    while let Some(word) = words.get(used) {
+        let word = word.as_str();
+        let ordinal = if ordinals { ordinal_value(word) } else { None };
+        // standalone "second" is usually the unit of time
+        if word == "second" && last == Part::Start {
+            break;
+        }
+        // zero is only a number on its own
+        if word == "zero" || (word == "zeroth" && ordinal.is_some()) {
+            if last == Part::Start {
+                return Some((0, ordinal.is_some(), 1));
+            }
+            break;
+        }
+        let value = SMALL.iter().position(|w| *w == word).map(|v| v as u64)
+            .or_else(|| TENS.iter().position(|w| *w == word).map(|v| 20 + 10 * v as u64))
+            .or_else(|| if word == "hundred" { Some(100) } else { None })
+            .or_else(|| SCALES.iter().find(|(w, _)| *w == word).map(|(_, v)| *v))
+            .or(ordinal);
+        let value = match value {
+            Some(v) => v,
+            None if word == "and" && matches!(last, Part::Hundred | Part::Scale) => {
+                // "and" only belongs to the number if more of the number follows it
+                let is_more = words.get(used + 1)
+                    .map(|w| SMALL.contains(&w.as_str()) || TENS.contains(&w.as_str()) || (ordinals && ordinal_value(w).map(|v| v < 100).unwrap_or(false)))
+                    .unwrap_or(false);
+                if !is_more {
+                    break;
+                }
+                last = Part::And;
+                used += 1;
+                continue;
+            },
+            None => break,
+        };
+        let is_after_number = matches!(last, Part::Small | Part::Teen | Part::Tens);
+        let part = if value == 100 && (word == "hundred" || word == "hundredth") {
+            if !is_after_number || current == 0 || current >= 100 {
+                break;
+            }
+            current *= 100;
+            Part::Hundred
+        } else if value >= 1_000 {
+            if current == 0 || value >= last_scale {
+                break;
+            }
+            total += current * value;
+            current = 0;
+            last_scale = value;
+            Part::Scale
+        } else if value >= 20 {
+            if is_after_number {
+                break;
+            }
+            current += value;
+            Part::Tens
+        } else {
+            // "twenty three" is one number, but "one two" and "twelve three" are two
+            if last == Part::Small || last == Part::Teen || (last == Part::Tens && (value == 0 || value >= 10)) {
+                break;
+            }
+            current += value;
+            if value >= 10 { Part::Teen } else { Part::Small }
+        };
+        last = part;
+        used += 1;
+        if ordinal.is_some() {
+            return Some((total + current, true, used));
+        }
+    }
+    if last == Part::And {
+        used -= 1; // the number ended before the word after "and"
+    }
+    if used == 0 {
+        None
+    } else {
+        Some((total + current, false, used))
+    }
+}
+
+// whether a (lowercase) word is part of a spoken number, like "nine", "hundred" or "first"
+pub fn is_number_word(word: &str) -> bool {
+    word == "hundred" || word == "oh"
+        || SMALL.contains(&word)
+        || TENS.contains(&word)
+        || SCALES.iter().any(|(w, _)| *w == word)
+        || ordinal_value(word).is_some()
+}
+
+fn ordinal_value(word: &str) -> Option<u64> {
+    SMALL_ORDINALS.iter().position(|w| *w == word).map(|v| v as u64)
+        .or_else(|| TENS_ORDINALS.iter().position(|w| *w == word).map(|v| 20 + 10 * v as u64))
+        .or_else(|| if word == "hundredth" { Some(100) } else { None })
+        .or_else(|| SCALE_ORDINALS.iter().find(|(w, _)| *w == word).map(|(_, v)| *v))
+}
+
+fn ordinal_suffix(value: u64) -> &'static str {
+    match (value % 10, value % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    }
+}
+
+fn digit(word: &str) -> Option<char> {
+    match word {
+        "oh" => Some('0'),
+        _ => SMALL[..10].iter().position(|w| *w == word).map(|v| (b'0' + v as u8) as char),
+    }
+}
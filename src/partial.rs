@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+use crate::command::EagerCommands;
+use crate::config::Config;
+use crate::pipeline::Pipeline;
+use crate::recognizer::IRecognitionStream;
+use crate::speech::{process_metadata, MetadataResult};
+use crate::TARGET_SAMPLE_RATE;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PartialConfig {
+    // audio to feed between intermediate decodes
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u32,
+    // intermediate decodes in a row with the same transcript for it to count as stable
+    #[serde(default = "default_stable_decodes")]
+    pub stable_decodes: u32,
+}
+
+fn default_interval_ms() -> u32 { 200 }
+
+fn default_stable_decodes() -> u32 { 2 }
+
+impl Default for PartialConfig {
+    fn default() -> Self {
+        PartialConfig {
+            interval_ms: default_interval_ms(),
+            stable_decodes: default_stable_decodes(),
+        }
+    }
+}
+
+// Partial transcript of the stream being decoded
+pub enum PartialEvent {
+    Changed(MetadataResult), // different from the last partial transcript
+    Stable(MetadataResult), // the same for stable_decodes intermediate decodes in a row
+}
+
+// Periodically decodes the live stream without finishing it
+pub struct PartialDecoder {
+    interval_samples: usize,
+    stable_decodes: u32,
+    pending_samples: usize, // fed since the last intermediate decode
+    last_phrase: String,
+    unchanged_decodes: u32,
+    pub eager: EagerCommands,
+}
+
+impl PartialDecoder {
+    pub fn new(conf: &PartialConfig) -> PartialDecoder {
+        PartialDecoder {
+            interval_samples: (conf.interval_ms * (TARGET_SAMPLE_RATE / 1_000)) as usize,
+            stable_decodes: conf.stable_decodes,
+            pending_samples: 0,
+            last_phrase: String::new(),
+            unchanged_decodes: 0,
+            eager: EagerCommands::default(),
+        }
+    }
+
+    // call after feeding audio to the stream; decodes once enough audio has been fed since the last decode
+    // length_ms is the amount of audio fed to the stream in total, and offset_ms is when the stream started
+    pub fn update(&mut self, stream: &mut dyn IRecognitionStream, fed_samples: usize, length_ms: u32, offset_ms: u64, casl_config: &Config, pipeline: &Pipeline) -> Option<PartialEvent> {
+        self.pending_samples += fed_samples;
+        if self.pending_samples < self.interval_samples {
+            return None;
+        }
+        self.pending_samples = 0;
+        let meta = process_metadata(&stream.intermediate_decode_with_metadata(1), length_ms, offset_ms, casl_config, pipeline);
+        if meta.phrase_raw != self.last_phrase {
+            self.last_phrase = meta.phrase_raw.clone();
+            self.unchanged_decodes = 1;
+        } else {
+            self.unchanged_decodes += 1;
+        }
+        if self.unchanged_decodes == self.stable_decodes.max(1) && !meta.phrase_raw.trim().is_empty() {
+            Some(PartialEvent::Stable(meta))
+        } else if self.unchanged_decodes == 1 {
+            Some(PartialEvent::Changed(meta))
+        } else {
+            None
+        }
+    }
+
+    // call when a new stream is started
+    pub fn reset(&mut self) {
+        self.pending_samples = 0;
+        self.last_phrase.clear();
+        self.unchanged_decodes = 0;
+    }
+}
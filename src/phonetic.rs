@@ -0,0 +1,357 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::PreprocessorConfig;
+use crate::preprocessor::ITextPreprocessor;
+
+const MIN_LETTERS: usize = 3; // shorter words are too easy to confuse with anything
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum VocabularyEntry {
+    Word(String),
+    SoundsLike {
+        text: String,
+        // how the text is pronounced, when it isn't spelled like it sounds (e.g. "ngnius" sounds like "ingenious")
+        sounds_like: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum PhoneticAlgorithm {
+    #[default]
+    Metaphone,
+    Soundex,
+}
+
+struct Entry {
+    text: String,
+    // (letters, phonetic code) of each way to say it
+    forms: Vec<(String, String)>,
+}
+
+// Replaces words (or runs of words) which sound like a vocabulary entry with that entry,
+// e.g. "in genie us" -> "ngnius"
+pub struct PhoneticCorrector {
+    entries: Vec<Entry>,
+    threshold: f64,
+    min_spelling: f64,
+    max_words: usize,
+    algorithm: PhoneticAlgorithm,
+    debug: bool,
+}
+
+impl PhoneticCorrector {
+    pub fn new(conf: &PreprocessorConfig) -> Result<PhoneticCorrector, String> {
+        if let PreprocessorConfig::Phonetic { vocabulary, path, threshold, min_spelling, max_words, algorithm, debug } = conf {
+            let mut vocabulary = vocabulary.clone();
+            if let Some(path) = path {
+                vocabulary.extend(read_vocabulary(path)?);
+            }
+            let entries = vocabulary.iter().map(|v| {
+                let (text, sounds_like) = match v {
+                    VocabularyEntry::Word(text) => (text, &[][..]),
+                    VocabularyEntry::SoundsLike { text, sounds_like } => (text, &sounds_like[..]),
+                };
+                Entry {
+                    text: text.clone(),
+                    forms: std::iter::once(text).chain(sounds_like.iter())
+                        .map(|s| {
+                            let letters = letters(s);
+                            let code = encode(&letters, algorithm);
+                            (letters, code)
+                        })
+                        .collect(),
+                }
+            }).collect();
+            Ok(PhoneticCorrector {
+                entries,
+                threshold: *threshold,
+                min_spelling: *min_spelling,
+                max_words: (*max_words).max(1),
+                algorithm: algorithm.clone(),
+                debug: *debug,
+            })
+        } else {panic!("Non-Phonetic config given to PhoneticCorrector");}
+    }
+
+    // (score, entry index, phonetic code, spelling similarity) of the closest entry
+    fn closest(&self, heard: &str) -> Option<(f64, usize, String, f64)> {
+        let heard_letters = letters(heard);
+        if heard_letters.chars().count() < MIN_LETTERS {
+            return None;
+        }
+        let heard_code = encode(&heard_letters, &self.algorithm);
+        let mut best: Option<(f64, usize, String, f64)> = None;
+        for (index, entry) in self.entries.iter().enumerate() {
+            for (form_letters, form_code) in &entry.forms {
+                // sounding alike matters as much as being spelled alike,
+                // but plenty of common words sound like an entry (e.g. "casual" and "casl")
+                let spelling = similarity(&heard_letters, form_letters);
+                if spelling < self.min_spelling {
+                    continue;
+                }
+                let score = (similarity(&heard_code, form_code) + spelling) / 2.0;
+                if best.as_ref().map(|b| score > b.0).unwrap_or(true) {
+                    best = Some((score, index, heard_code.clone(), spelling));
+                }
+            }
+        }
+        best
+    }
+}
+
+impl ITextPreprocessor for PhoneticCorrector {
+    fn process(&self, input: &str) -> String {
+        let words: Vec<&str> = input.split_whitespace().collect();
+        let mut result: Vec<String> = Vec::with_capacity(words.len());
+        let mut i = 0;
+        while i < words.len() {
+            // the best match of any run of words starting here (longer runs win ties)
+            let mut best: Option<(f64, usize, usize, String, f64)> = None;
+            for n in (1..=self.max_words.min(words.len() - i)).rev() {
+                let heard = words[i..i + n].join(" ");
+                if let Some((score, index, code, spelling)) = self.closest(&heard) {
+                    if score >= self.threshold && best.as_ref().map(|b| score > b.0).unwrap_or(true) {
+                        best = Some((score, index, n, code, spelling));
+                    }
+                }
+            }
+            match best {
+                Some((score, index, n, code, spelling)) => {
+                    let entry = &self.entries[index];
+                    let heard = words[i..i + n].join(" ");
+                    if self.debug && heard != entry.text {
+                        println!("Phonetic: `{}` -> `{}` (score {:.2}: sounds like {}, spelling {:.2})",
+                                 heard, &entry.text, score, code, spelling);
+                    }
+                    result.push(entry.text.clone());
+                    i += n;
+                },
+                None => {
+                    result.push(words[i].to_owned());
+                    i += 1;
+                }
+            }
+        }
+        result.join(" ")
+    }
+}
+
+// one entry per line, optionally followed by `=` and what it sounds like (e.g. `ngnius = ingenious, in genius`)
+fn read_vocabulary(path: &str) -> Result<Vec<VocabularyEntry>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read vocabulary file {} ({})", path, e))?;
+    Ok(text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once('=') {
+            Some((text, sounds_like)) => VocabularyEntry::SoundsLike {
+                text: text.trim().to_owned(),
+                sounds_like: sounds_like.split(',').map(|s| s.trim().to_owned()).filter(|s| !s.is_empty()).collect(),
+            },
+            None => VocabularyEntry::Word(line.to_owned()),
+        })
+        .collect())
+}
+
+// lowercase letters only, so that "in genie us" and "ingenious" can be compared
+fn letters(text: &str) -> String {
+    text.chars()
+        .filter(|c| c.is_alphabetic())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+fn encode(letters: &str, algorithm: &PhoneticAlgorithm) -> String {
+    match algorithm {
+        PhoneticAlgorithm::Metaphone => metaphone(letters),
+        PhoneticAlgorithm::Soundex => soundex(letters),
+    }
+}
+
+// 1.0 for the same text, down to 0.0 for nothing in common (by edit distance)
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - levenshtein(&a, &b) as f64 / longest as f64
+}
+
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == cb { 0 } else { 1 };
+            current.push((previous[j + 1] + 1).min(current[j] + 1).min(previous[j] + cost));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn is_vowel(c: char) -> bool {
+    matches!(c, 'a' | 'e' | 'i' | 'o' | 'u')
+}
+
+// Simplified Metaphone (the original rules, without the exceptions for rare spellings)
+fn metaphone(letters: &str) -> String {
+    let mut w: Vec<char> = letters.chars().filter(|c| c.is_ascii_lowercase()).collect();
+    if w.len() >= 2 && matches!((w[0], w[1]), ('a', 'e') | ('g', 'n') | ('k', 'n') | ('p', 'n') | ('w', 'r')) {
+        w.remove(0);
+    }
+    if w.first() == Some(&'x') {
+        w[0] = 's';
+    }
+    if w.len() >= 2 && w[0] == 'w' && w[1] == 'h' {
+        w.remove(1);
+    }
+    let at = |i: usize| w.get(i).copied().unwrap_or(' ');
+    let mut code = String::new();
+    for i in 0..w.len() {
+        let (c, prev, next, after) = (w[i], if i > 0 { w[i - 1] } else { ' ' }, at(i + 1), at(i + 2));
+        if c == prev && c != 'c' {
+            continue;
+        }
+        match c {
+            'a' | 'e' | 'i' | 'o' | 'u' => if i == 0 { code.push('A') },
+            'b' => if !(prev == 'm' && i + 1 == w.len()) { code.push('B') },
+            'c' => {
+                if next == 'i' && after == 'a' {
+                    code.push('X');
+                } else if next == 'h' {
+                    code.push(if prev == 's' { 'K' } else { 'X' });
+                } else if matches!(next, 'i' | 'e' | 'y') {
+                    if prev != 's' {
+                        code.push('S');
+                    }
+                } else {
+                    code.push('K');
+                }
+            },
+            'd' => code.push(if next == 'g' && matches!(after, 'e' | 'i' | 'y') { 'J' } else { 'T' }),
+            'g' => {
+                if next == 'h' && !is_vowel(after) {
+                    continue;
+                }
+                if next == 'n' && (i + 2 == w.len() || (after == 'e' && at(i + 3) == 'd' && i + 4 == w.len())) {
+                    continue;
+                }
+                if prev == 'd' && matches!(next, 'e' | 'i' | 'y') {
+                    continue;
+                }
+                code.push(if matches!(next, 'e' | 'i' | 'y') { 'J' } else { 'K' });
+            },
+            'h' => if !matches!(prev, 'c' | 's' | 'p' | 't' | 'g') && is_vowel(next) { code.push('H') },
+            'k' => if prev != 'c' { code.push('K') },
+            'p' => code.push(if next == 'h' { 'F' } else { 'P' }),
+            'q' => code.push('K'),
+            's' => code.push(if next == 'h' || (next == 'i' && matches!(after, 'o' | 'a')) { 'X' } else { 'S' }),
+            't' => {
+                if next == 'i' && matches!(after, 'o' | 'a') {
+                    code.push('X');
+                } else if next == 'h' {
+                    code.push('0'); // th
+                } else if !(next == 'c' && after == 'h') {
+                    code.push('T');
+                }
+            },
+            'v' => code.push('F'),
+            'w' | 'y' => if is_vowel(next) { code.push(c.to_ascii_uppercase()) },
+            'x' => code.push_str("KS"),
+            'z' => code.push('S'),
+            _ => code.push(c.to_ascii_uppercase()),
+        }
+    }
+    code
+}
+
+// American Soundex, like "R163" for "robert" and "rupert"
+fn soundex(letters: &str) -> String {
+    let digit = |c: char| match c {
+        'b' | 'f' | 'p' | 'v' => '1',
+        'c' | 'g' | 'j' | 'k' | 'q' | 's' | 'x' | 'z' => '2',
+        'd' | 't' => '3',
+        'l' => '4',
+        'm' | 'n' => '5',
+        'r' => '6',
+        _ => '0', // vowels, h, w and y
+    };
+    let mut chars = letters.chars().filter(|c| c.is_ascii_lowercase());
+    let first = match chars.next() {
+        Some(c) => c,
+        None => return String::new(),
+    };
+    let mut code = first.to_ascii_uppercase().to_string();
+    let mut last = digit(first);
+    for c in chars {
+        let d = digit(c);
+        if d != '0' && d != last {
+            code.push(d);
+            if code.len() == 4 {
+                break;
+            }
+        }
+        // h and w don't separate letters with the same code, but vowels do
+        if c != 'h' && c != 'w' {
+            last = d;
+        }
+    }
+    while code.len() < 4 {
+        code.push('0');
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn correct(text: &str) -> String {
+        let conf: PreprocessorConfig = serde_json::from_str(r#"{
+            "type": "Phonetic",
+            "vocabulary": [{"text": "ngnius", "sounds_like": ["ingenious"]}, "spotify", "casl", "kubernetes"]
+        }"#).expect("Invalid test config");
+        PhoneticCorrector::new(&conf).expect("Invalid test vocabulary").process(text)
+    }
+
+    #[test]
+    fn encodes_metaphone() {
+        assert_eq!(metaphone("casl"), "KSL");
+        assert_eq!(metaphone("casual"), "KSL");
+        assert_eq!(metaphone("ingenious"), "ANJNS");
+        assert_eq!(metaphone("thumb"), "0M");
+        assert_eq!(metaphone("phone"), "FN");
+        assert_eq!(metaphone("knight"), "NT");
+        assert_eq!(metaphone("xray"), "SR");
+    }
+
+    #[test]
+    fn encodes_soundex() {
+        assert_eq!(soundex("robert"), "R163");
+        assert_eq!(soundex("rupert"), "R163");
+        assert_eq!(soundex("ashcraft"), "A261");
+        assert_eq!(soundex("tymczak"), "T522");
+        assert_eq!(soundex("pfister"), "P236");
+        assert_eq!(soundex(""), "");
+    }
+
+    #[test]
+    fn replaces_words_which_sound_and_look_alike() {
+        assert_eq!(correct("hey in genie us"), "hey ngnius");
+        assert_eq!(correct("play spotty fy"), "play spotify");
+        assert_eq!(correct("deploy to cuber netties"), "deploy to kubernetes");
+        assert_eq!(correct("open casl"), "open casl");
+    }
+
+    #[test]
+    fn keeps_words_which_only_sound_alike() {
+        // (KSL, like casl, but spelled too differently)
+        assert_eq!(correct("open casual"), "open casual");
+        assert_eq!(correct("open the castle"), "open the castle");
+        assert_eq!(correct("hello world"), "hello world");
+    }
+}
@@ -0,0 +1,109 @@
+use regex::{Regex, RegexBuilder};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use crate::command::ICommand;
+use crate::command_api::Payload;
+use crate::config::{CommandConfig, Config};
+use crate::preprocessor::ITextPreprocessor;
+
+const BUILD_ROUNDS: u32 = 10;
+const UTTERANCE_ROUNDS: u32 = 1_000;
+
+// Pre-processors and commands built from the config once, with their regexes compiled,
+// so that handling an utterance doesn't read files or compile anything
+pub struct Pipeline {
+    pub preprocessors: Vec<Box<dyn ITextPreprocessor>>,
+    pub commands: Vec<PipelineCommand>,
+    pub wake_phrase: Option<Regex>,
+}
+
+pub struct PipelineCommand {
+    pub config: CommandConfig,
+    precondition: Regex,
+    command: Box<dyn ICommand>,
+}
+
+impl Pipeline {
+    pub fn new(casl_config: &Config) -> Result<Pipeline, String> {
+        if casl_config.candidates == 0 {
+            return Err("candidates must be at least 1".to_owned());
+        }
+        let mut preprocessors = Vec::with_capacity(casl_config.preprocessors.len());
+        for (i, pre) in casl_config.preprocessors.iter().enumerate() {
+            preprocessors.push(pre.preprocessor().map_err(|e| format!("pre-processor {}: {}", i + 1, e))?);
+        }
+        let mut commands = Vec::with_capacity(casl_config.commands.len());
+        for (i, cmd) in casl_config.commands.iter().enumerate() {
+            commands.push(PipelineCommand::new(cmd).map_err(|e| format!("command {}: {}", i + 1, e))?);
+        }
+        let wake_phrase = match &casl_config.wake {
+            Some(wake) => crate::wake::wake_phrase_regex(&wake.phrases).map_err(|e| format!("wake: {}", e))?,
+            None => None,
+        };
+        Ok(Pipeline {
+            preprocessors,
+            commands,
+            wake_phrase,
+        })
+    }
+
+    pub fn preprocess(&self, text: &str) -> String {
+        let mut processed_text = text.to_owned();
+        for pre in &self.preprocessors {
+            processed_text = pre.process(&processed_text);
+        }
+        processed_text
+    }
+}
+
+impl PipelineCommand {
+    pub fn new(conf: &CommandConfig) -> Result<PipelineCommand, String> {
+        Ok(PipelineCommand {
+            config: conf.clone(),
+            precondition: compile_precondition(conf.precondition())?,
+            command: conf.command()?,
+        })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.precondition.is_match(text)
+    }
+
+    pub fn run(&self, payload: &Payload) -> Option<JoinHandle<()>> {
+        self.command.run(payload)
+    }
+}
+
+pub fn compile_precondition(precondition: &str) -> Result<Regex, String> {
+    RegexBuilder::new(precondition)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| format!("invalid precondition regex `{}` ({})", precondition, e))
+}
+
+// Time building the pipeline (which used to happen for every utterance) and handling the phrases with it.
+// Commands are only matched, not run.
+pub fn benchmark(casl_config: &Config, phrases: &[String]) {
+    let start = Instant::now();
+    for _ in 0..BUILD_ROUNDS {
+        Pipeline::new(casl_config).expect("Invalid config");
+    }
+    let build_us = start.elapsed().as_secs_f64() * 1e6 / BUILD_ROUNDS as f64;
+    let pipeline = Pipeline::new(casl_config).expect("Invalid config");
+    let mut matches = 0;
+    let start = Instant::now();
+    for _ in 0..UTTERANCE_ROUNDS {
+        for phrase in phrases {
+            let meta = crate::speech::process_text(phrase, &pipeline);
+            matches += pipeline.commands.iter()
+                .filter(|c| c.is_match(if c.config.use_raw() { &meta.phrase_raw } else { &meta.phrase }))
+                .count();
+        }
+    }
+    let utterance_us = start.elapsed().as_secs_f64() * 1e6 / (UTTERANCE_ROUNDS as usize * phrases.len()) as f64;
+    println!("Benchmarked {} phrase(s) {} times ({} pre-processors, {} commands, {} matches)",
+             phrases.len(), UTTERANCE_ROUNDS, pipeline.preprocessors.len(), pipeline.commands.len(), matches);
+    println!("Building the pipeline: {:.1}us (once, when CASL starts)", build_us);
+    println!("Pre-processing and matching an utterance: {:.1}us", utterance_us);
+}
@@ -1,37 +1,103 @@
-use regex::RegexBuilder;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use crate::config;
+use crate::pipeline::compile_precondition;
 
-pub trait ITextPreprocessor {
+pub trait ITextPreprocessor: Send + Sync {
     fn process(&self, input: &str) -> String;
 }
 
+// lowercase words, for matching phrases word by word
+pub fn split_words(text: &str) -> Vec<String> {
+    text.split_whitespace().map(|w| w.to_lowercase()).collect()
+}
+
+pub fn split_phrases(texts: &[String]) -> Vec<Vec<String>> {
+    texts.iter().map(|t| split_words(t)).filter(|p| !p.is_empty()).collect()
+}
+
+pub fn starts_with_phrase(words: &[String], phrase: &[String]) -> bool {
+    words.len() >= phrase.len() && phrase.iter().zip(words).all(|(p, w)| p == w)
+}
+
+// how many words the longest phrase at the start of words uses
+pub fn longest_match(phrases: &[Vec<String>], words: &[String]) -> Option<usize> {
+    phrases.iter()
+        .filter(|p| starts_with_phrase(words, p))
+        .map(|p| p.len())
+        .max()
+}
+
+// Remap rules, either as a list (applied in order) or as a map of pattern to replacement
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum RemapMappings {
+    List(Vec<RemapRule>),
+    Map(HashMap<String, String>),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RemapRule {
+    pub pattern: String,
+    pub replacement: String,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    // only match whole words (wraps the pattern in \b)
+    #[serde(default)]
+    pub word_boundary: bool,
+}
+
+impl RemapMappings {
+    // rules in the order they are applied
+    pub fn rules(&self) -> Vec<RemapRule> {
+        match self {
+            RemapMappings::List(rules) => rules.clone(),
+            RemapMappings::Map(maps) => {
+                // maps have no order, so longer patterns go first (e.g. "x ray" before "ray")
+                let mut rules: Vec<RemapRule> = maps.iter().map(|(key, val)| RemapRule {
+                    pattern: key.clone(),
+                    replacement: val.clone(),
+                    case_sensitive: false,
+                    word_boundary: false,
+                }).collect();
+                rules.sort_by(|a, b| b.pattern.len().cmp(&a.pattern.len()).then_with(|| a.pattern.cmp(&b.pattern)));
+                rules
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct SimpleMapper {
-    mappings: HashMap<String, String>,
+    rules: Vec<(Regex, String)>, // (pattern, replacement) in the order they are applied
 }
 
 impl SimpleMapper {
-    pub fn from_mappings(maps: &HashMap<String, String>) -> SimpleMapper {
-        SimpleMapper {
-            mappings: maps.clone(),
+    pub fn from_mappings(maps: &RemapMappings) -> Result<SimpleMapper, String> {
+        let mut rules = Vec::new();
+        for rule in maps.rules() {
+            let pattern = if rule.word_boundary { format!(r"\b(?:{})\b", &rule.pattern) } else { rule.pattern.clone() };
+            let re = RegexBuilder::new(&pattern)
+                .case_insensitive(!rule.case_sensitive)
+                .build()
+                .map_err(|e| format!("invalid Remap regex `{}` ({})", &rule.pattern, e))?;
+            rules.push((re, rule.replacement));
         }
+        Ok(SimpleMapper {
+            rules,
+        })
     }
 }
 
 impl ITextPreprocessor for SimpleMapper {
     fn process(&self, input: &str) -> String {
-        let mut result = String::from(input.clone());
-        for (key, val) in &self.mappings {
-            let re = RegexBuilder::new(key)
-                .case_insensitive(true)
-                .build()
-                .expect(&format!("Failed to compile the regex {} for SimpleMapper", key));
+        let mut result = String::from(input);
+        for (re, replacement) in &self.rules {
             if re.is_match(&result) {
-                let replacement: &str = &val.clone();
-                result = re.replace_all(&result, replacement).to_string();
+                result = re.replace_all(&result, replacement.as_str()).to_string();
             }
         }
         result
@@ -39,20 +105,19 @@ impl ITextPreprocessor for SimpleMapper {
 }
 
 pub struct RedirectConfig {
-    path: PathBuf,
     processor: Box<dyn ITextPreprocessor>,
 }
 
 impl RedirectConfig {
-    pub fn from_path(path: &Path) -> RedirectConfig {
-        let json_file = std::fs::File::open(path).unwrap();
+    pub fn from_path(path: &Path) -> Result<RedirectConfig, String> {
+        let json_file = std::fs::File::open(path)
+            .map_err(|e| format!("failed to open {} ({})", path.display(), e))?;
         let json_reader = std::io::BufReader::new(json_file);
         let preprocessor_conf: config::PreprocessorConfig = serde_json::from_reader(json_reader)
-            .expect(&("Unable to parse JSON file ".to_owned() + path.to_str().unwrap()));
-        RedirectConfig {
-            path: path.clone().to_owned(),
-            processor: preprocessor_conf.preprocessor(),
-        }
+            .map_err(|e| format!("unable to parse JSON file {} ({})", path.display(), e))?;
+        Ok(RedirectConfig {
+            processor: preprocessor_conf.preprocessor()?,
+        })
     }
 }
 
@@ -62,8 +127,62 @@ impl ITextPreprocessor for RedirectConfig {
     }
 }
 
-impl Clone for RedirectConfig {
-    fn clone(&self) -> Self {
-        RedirectConfig::from_path(&self.path)
+// Pre-processors which run one after another, like the top level list in the config
+pub struct PreprocessorGroup {
+    processors: Vec<Box<dyn ITextPreprocessor>>,
+}
+
+impl PreprocessorGroup {
+    pub fn from_configs(confs: &[config::PreprocessorConfig]) -> Result<PreprocessorGroup, String> {
+        let mut processors = Vec::with_capacity(confs.len());
+        for (i, conf) in confs.iter().enumerate() {
+            processors.push(conf.preprocessor().map_err(|e| format!("pre-processor {}: {}", i + 1, e))?);
+        }
+        Ok(PreprocessorGroup {
+            processors,
+        })
+    }
+}
+
+impl ITextPreprocessor for PreprocessorGroup {
+    fn process(&self, input: &str) -> String {
+        let mut result = input.to_owned();
+        for pre in &self.processors {
+            result = pre.process(&result);
+        }
+        result
+    }
+}
+
+// Runs another pre-processor only when the text matches precondition and doesn't match unless
+pub struct ConditionalPreprocessor {
+    precondition: Option<Regex>,
+    unless: Option<Regex>,
+    processor: Box<dyn ITextPreprocessor>,
+}
+
+impl ConditionalPreprocessor {
+    pub fn new(conf: &config::PreprocessorConfig) -> Result<ConditionalPreprocessor, String> {
+        if let config::PreprocessorConfig::When { precondition, unless, preprocessor } = conf {
+            Ok(ConditionalPreprocessor {
+                precondition: precondition.as_deref().map(compile_precondition).transpose()
+                    .map_err(|e| format!("When precondition: {}", e))?,
+                unless: unless.as_deref().map(compile_precondition).transpose()
+                    .map_err(|e| format!("When unless: {}", e))?,
+                processor: preprocessor.preprocessor()?,
+            })
+        } else {panic!("Non-When config given to ConditionalPreprocessor");}
+    }
+}
+
+impl ITextPreprocessor for ConditionalPreprocessor {
+    fn process(&self, input: &str) -> String {
+        let is_match = self.precondition.as_ref().map(|re| re.is_match(input)).unwrap_or(true)
+            && !self.unless.as_ref().map(|re| re.is_match(input)).unwrap_or(false);
+        if is_match {
+            self.processor.process(input)
+        } else {
+            input.to_owned()
+        }
     }
-}
\ No newline at end of file
+}
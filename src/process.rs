@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::PreprocessorConfig;
+use crate::preprocessor::ITextPreprocessor;
+
+// one JSON object per line, in each direction
+#[derive(Serialize)]
+struct Request<'a> {
+    id: u64,
+    text: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+struct ChildProcess {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>, // from stdout
+    next_id: u64,
+}
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Sends every phrase to a long-lived child process and uses the text it sends back.
+// When the process fails, times out or has exited, the phrase is passed on unchanged
+// (and the process is started again for the next phrase).
+pub struct ExternalPreprocessor {
+    command: String,
+    args: Vec<String>,
+    timeout: Duration,
+    child: Mutex<Option<ChildProcess>>,
+}
+
+impl ExternalPreprocessor {
+    pub fn new(conf: &PreprocessorConfig) -> Result<ExternalPreprocessor, String> {
+        if let PreprocessorConfig::Process { command, args, timeout_ms } = conf {
+            let external = ExternalPreprocessor {
+                command: command.clone(),
+                args: args.clone(),
+                timeout: Duration::from_millis(*timeout_ms),
+                child: Mutex::new(None),
+            };
+            // a command which can't start at all is a config error
+            *external.child.lock().unwrap() = Some(external.spawn()?);
+            Ok(external)
+        } else {panic!("Non-Process config given to ExternalPreprocessor");}
+    }
+
+    fn spawn(&self) -> Result<ChildProcess, String> {
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| format!("failed to start `{}` ({})", &self.command, e))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (tx, rx) = channel();
+        // stdout is read on its own thread, so that waiting for a response can time out
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => if tx.send(line).is_err() { break },
+                    Err(_) => break,
+                }
+            }
+        });
+        Ok(ChildProcess {
+            child,
+            stdin,
+            lines: rx,
+            next_id: 0,
+        })
+    }
+
+    fn rewrite(&self, child: &mut ChildProcess, input: &str) -> Result<String, String> {
+        let id = child.next_id;
+        child.next_id += 1;
+        let mut request = serde_json::to_string(&Request { id, text: input }).expect("Failed to serialize Request");
+        request.push('\n');
+        child.stdin.write_all(request.as_bytes())
+            .and_then(|_| child.stdin.flush())
+            .map_err(|e| format!("failed to send text ({})", e))?;
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let line = match child.lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => return Err(format!("no response within {}ms", self.timeout.as_millis())),
+                Err(RecvTimeoutError::Disconnected) => return Err("process exited".to_owned()),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let resp: Response = serde_json::from_str(&line)
+                .map_err(|e| format!("invalid response `{}` ({})", line, e))?;
+            // (a response to an earlier text which timed out)
+            if resp.id.map(|i| i != id).unwrap_or(false) {
+                continue;
+            }
+            if let Some(err) = resp.error {
+                return Err(err);
+            }
+            return resp.text.ok_or_else(|| format!("response `{}` has no text", line));
+        }
+    }
+}
+
+impl ITextPreprocessor for ExternalPreprocessor {
+    fn process(&self, input: &str) -> String {
+        let mut child = self.child.lock().unwrap();
+        if child.is_none() {
+            match self.spawn() {
+                Ok(c) => *child = Some(c),
+                Err(e) => {
+                    println!("Process pre-processor {}", e);
+                    return input.to_owned();
+                }
+            }
+        }
+        match self.rewrite(child.as_mut().unwrap(), input) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("Process pre-processor `{}` error: {}", &self.command, e);
+                let c = child.as_mut().unwrap();
+                // (a line left over now would only be a late response, which is skipped anyway)
+                let has_exited = matches!(c.lines.try_recv(), Err(TryRecvError::Disconnected))
+                    || c.child.try_wait().map(|s| s.is_some()).unwrap_or(true);
+                if has_exited {
+                    *child = None;
+                }
+                input.to_owned()
+            }
+        }
+    }
+}
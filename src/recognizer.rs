@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::speech::TIMESTEP_TO_MS;
+use crate::TARGET_SAMPLE_RATE;
+
+// Speech-to-text engine
+pub trait ISpeechRecognizer {
+    fn create_stream(&mut self) -> Box<dyn IRecognitionStream>;
+    // use another external scorer (or none); only called while no stream is open
+    fn set_scorer(&mut self, _path: Option<&str>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Audio stream being decoded by a speech-to-text engine
+pub trait IRecognitionStream {
+    fn feed_audio(&mut self, buffer: &[i16]);
+    // transcript of the audio so far, without ending the stream
+    fn intermediate_decode_with_metadata(&mut self, num_results: u32) -> Metadata;
+    fn finish_with_metadata(self: Box<Self>, num_results: u32) -> Metadata;
+}
+
+// Engine-independent copy of deepspeech::Metadata
+#[derive(Clone)]
+pub struct Metadata {
+    pub transcripts: Vec<CandidateTranscript>,
+}
+
+#[derive(Clone)]
+pub struct CandidateTranscript {
+    pub confidence: f64,
+    pub tokens: Vec<TokenMetadata>,
+}
+
+#[derive(Clone)]
+pub struct TokenMetadata {
+    pub text: String,
+    pub timestep: u32,
+}
+
+#[cfg(feature = "deepspeech")]
+pub struct DeepSpeechRecognizer {
+    model: deepspeech::Model,
+}
+
+#[cfg(feature = "deepspeech")]
+impl DeepSpeechRecognizer {
+    pub fn new(model: &str, scorer: &Option<String>, hot_words: &[(String, f32)]) -> DeepSpeechRecognizer {
+        let mut speech2text = deepspeech::Model::load_from_files(std::path::Path::new(model))
+            .expect(&format!("Failed to load DeepSpeech model {}", model));
+        if let Some(scorer) = scorer {
+            speech2text.enable_external_scorer(std::path::Path::new(scorer))
+                .expect(&format!("Failed to load DeepSpeech scorer {}", scorer));
+        }
+        for (word, boost) in hot_words {
+            speech2text.add_hot_word(word, *boost)
+                .expect(&format!("Failed to add DeepSpeech hot word {}", word));
+        }
+        DeepSpeechRecognizer {
+            model: speech2text,
+        }
+    }
+}
+
+#[cfg(feature = "deepspeech")]
+impl ISpeechRecognizer for DeepSpeechRecognizer {
+    fn create_stream(&mut self) -> Box<dyn IRecognitionStream> {
+        Box::new(DeepSpeechStream {
+            stream: self.model.create_stream().unwrap(),
+        })
+    }
+
+    fn set_scorer(&mut self, path: Option<&str>) -> Result<(), String> {
+        match path {
+            Some(path) => {
+                let result = self.model.enable_external_scorer(std::path::Path::new(path));
+                if let Err(e) = result {
+                    self.model.disable_external_scorer().unwrap_or(());
+                    return Err(e.to_string());
+                }
+                Ok(())
+            },
+            None => self.model.disable_external_scorer().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "deepspeech")]
+pub struct DeepSpeechStream {
+    stream: deepspeech::Stream,
+}
+
+#[cfg(feature = "deepspeech")]
+impl IRecognitionStream for DeepSpeechStream {
+    fn feed_audio(&mut self, buffer: &[i16]) {
+        self.stream.feed_audio(buffer);
+    }
+
+    fn intermediate_decode_with_metadata(&mut self, num_results: u32) -> Metadata {
+        convert_metadata(&self.stream.intermediate_decode_with_metadata(num_results).unwrap())
+    }
+
+    fn finish_with_metadata(self: Box<Self>, num_results: u32) -> Metadata {
+        convert_metadata(&self.stream.finish_with_metadata(num_results).unwrap())
+    }
+}
+
+#[cfg(feature = "deepspeech")]
+fn convert_metadata(metadata: &deepspeech::Metadata) -> Metadata {
+    Metadata {
+        transcripts: metadata.transcripts().iter().map(|t| CandidateTranscript {
+            confidence: t.confidence(),
+            tokens: t.tokens().iter().map(|token| TokenMetadata {
+                text: token.text().unwrap().to_owned(),
+                timestep: token.timestep(),
+            }).collect(),
+        }).collect(),
+    }
+}
+
+// Scripted transcript for the Mock recognizer
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MockTranscript {
+    pub phrases: Vec<MockPhrase>,
+    #[serde(default)]
+    pub confidence: f64,
+    // less likely candidate transcripts, for when more than one is asked for
+    #[serde(default)]
+    pub alternates: Vec<MockTranscript>,
+}
+
+// Text which is "heard" starting at start_ms (relative to the start of the stream)
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MockPhrase {
+    pub text: String,
+    pub start_ms: u32,
+}
+
+// Deterministic recognizer which ignores audio and returns the scripted transcripts in order,
+// one per stream (and empty transcripts once the script runs out)
+// Intermediate transcripts only include text "heard" before the end of the audio fed so far.
+pub struct MockRecognizer {
+    results: VecDeque<MockTranscript>,
+}
+
+impl MockRecognizer {
+    pub fn new(results: &[MockTranscript]) -> MockRecognizer {
+        MockRecognizer {
+            results: results.iter().cloned().collect(),
+        }
+    }
+}
+
+impl ISpeechRecognizer for MockRecognizer {
+    fn create_stream(&mut self) -> Box<dyn IRecognitionStream> {
+        Box::new(MockStream {
+            result: self.results.pop_front(),
+            fed_samples: 0,
+        })
+    }
+}
+
+pub struct MockStream {
+    result: Option<MockTranscript>,
+    fed_samples: usize,
+}
+
+impl MockStream {
+    // up to num_results transcripts of the first `until_ms` of the stream (or all of it)
+    fn decode(&self, num_results: u32, until_ms: Option<u32>) -> Metadata {
+        let transcripts = match &self.result {
+            Some(result) => std::iter::once(result).chain(result.alternates.iter())
+                .take(num_results.max(1) as usize)
+                .map(|r| Self::candidate(r, until_ms))
+                .collect(),
+            None => vec![CandidateTranscript {
+                confidence: 0.0,
+                tokens: Vec::new(),
+            }],
+        };
+        Metadata {
+            transcripts,
+        }
+    }
+
+    fn candidate(result: &MockTranscript, until_ms: Option<u32>) -> CandidateTranscript {
+        let mut transcript = CandidateTranscript {
+            confidence: result.confidence,
+            tokens: Vec::new(),
+        };
+        for phrase in &result.phrases {
+            if let Some(last) = transcript.tokens.last() {
+                // word separator right after the previous phrase, like DeepSpeech
+                transcript.tokens.push(TokenMetadata {
+                    text: " ".to_owned(),
+                    timestep: last.timestep + 1,
+                });
+            }
+            // one token per character, like DeepSpeech
+            let start_timestep = phrase.start_ms / TIMESTEP_TO_MS;
+            for (i, c) in phrase.text.chars().enumerate() {
+                let timestep = start_timestep + i as u32;
+                if until_ms.map(|ms| timestep * TIMESTEP_TO_MS >= ms).unwrap_or(false) {
+                    break;
+                }
+                transcript.tokens.push(TokenMetadata {
+                    text: c.to_string(),
+                    timestep,
+                });
+            }
+        }
+        transcript
+    }
+}
+
+impl IRecognitionStream for MockStream {
+    fn feed_audio(&mut self, buffer: &[i16]) {
+        self.fed_samples += buffer.len();
+    }
+
+    fn intermediate_decode_with_metadata(&mut self, num_results: u32) -> Metadata {
+        self.decode(num_results, Some(self.fed_samples as u32 / (TARGET_SAMPLE_RATE / 1_000)))
+    }
+
+    fn finish_with_metadata(self: Box<Self>, num_results: u32) -> Metadata {
+        self.decode(num_results, None)
+    }
+}
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::command::MatchedCommand;
+use crate::speech::MetadataResult;
+use crate::TARGET_SAMPLE_RATE;
+
+const CLIP_PREFIX: &str = "clip-";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RecorderConfig {
+    pub path: String,
+    // oldest clips are deleted once there are more clips than this
+    #[serde(default)]
+    pub max_clips: Option<usize>,
+    // oldest clips are deleted once all clips take up more space than this
+    #[serde(default)]
+    pub max_size_mb: Option<u64>,
+    // also save utterances which were decoded into no text (e.g. noise)
+    #[serde(default)]
+    pub include_empty: bool,
+}
+
+// JSON sidecar written beside each clip
+#[derive(Serialize)]
+struct ClipInfo<'a> {
+    time_ms: u128, // when the utterance was decoded, since the unix epoch
+    audio: &'a str, // file name of the clip
+    length_ms: u32,
+    #[serde(flatten)]
+    result: &'a MetadataResult,
+    commands: &'a [MatchedCommand],
+}
+
+// Saves decoded utterances as 16kHz mono WAV clips, with their transcript
+pub struct Recorder {
+    path: PathBuf,
+    max_clips: Option<usize>,
+    max_bytes: Option<u64>,
+    include_empty: bool,
+    sequence: u32, // distinguishes clips saved in the same millisecond
+}
+
+impl Recorder {
+    pub fn new(conf: &RecorderConfig) -> Recorder {
+        std::fs::create_dir_all(&conf.path)
+            .unwrap_or_else(|e| panic!("Failed to create recording directory {} ({})", &conf.path, e));
+        Recorder {
+            path: PathBuf::from(&conf.path),
+            max_clips: conf.max_clips,
+            max_bytes: conf.max_size_mb.map(|mb| mb * 1_000_000),
+            include_empty: conf.include_empty,
+            sequence: 0,
+        }
+    }
+
+    pub fn record(&mut self, samples: &[i16], meta: &MetadataResult, commands: &[MatchedCommand]) {
+        if !self.include_empty && meta.phrase_raw.trim().is_empty() {
+            return;
+        }
+        let time_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        // zero-padded, so that clips sort by name in the order they were saved
+        let name = format!("{}{:013}-{:04}", CLIP_PREFIX, time_ms, self.sequence);
+        self.sequence = (self.sequence + 1) % 10_000;
+        let wav_name = format!("{}.wav", &name);
+        if let Err(e) = write_wav(&self.path.join(&wav_name), samples) {
+            println!("Failed to save utterance clip {}: {}", &wav_name, e);
+            return;
+        }
+        let info = ClipInfo {
+            time_ms,
+            audio: &wav_name,
+            length_ms: samples.len() as u32 / (TARGET_SAMPLE_RATE / 1_000),
+            result: meta,
+            commands,
+        };
+        let json = serde_json::to_string_pretty(&info).expect("Failed to serialize ClipInfo");
+        if let Err(e) = std::fs::write(self.path.join(format!("{}.json", &name)), json) {
+            println!("Failed to save utterance clip transcript {}.json: {}", &name, e);
+        }
+        self.enforce_limits();
+    }
+
+    // delete the oldest clips (and their sidecars) until the limits are met
+    fn enforce_limits(&self) {
+        if self.max_clips.is_none() && self.max_bytes.is_none() {
+            return;
+        }
+        let entries = match std::fs::read_dir(&self.path) {
+            Ok(e) => e,
+            Err(e) => {
+                println!("Failed to list recording directory {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        // (name without extension, size of clip and sidecar)
+        let mut clips: Vec<(String, u64)> = Vec::new();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if file_name.starts_with(CLIP_PREFIX) && file_name.ends_with(".wav") {
+                let stem = file_name.trim_end_matches(".wav").to_owned();
+                let size = entry.metadata().map(|m| m.len()).unwrap_or(0)
+                    + std::fs::metadata(self.path.join(format!("{}.json", &stem))).map(|m| m.len()).unwrap_or(0);
+                clips.push((stem, size));
+            }
+        }
+        clips.sort();
+        let mut count = clips.len();
+        let mut total_bytes: u64 = clips.iter().map(|c| c.1).sum();
+        for (stem, size) in &clips {
+            let too_many = self.max_clips.map(|max| count > max).unwrap_or(false);
+            let too_big = self.max_bytes.map(|max| total_bytes > max).unwrap_or(false);
+            if !too_many && !too_big {
+                break;
+            }
+            std::fs::remove_file(self.path.join(format!("{}.wav", stem))).unwrap_or(());
+            std::fs::remove_file(self.path.join(format!("{}.json", stem))).unwrap_or(()); // ignore errors
+            count -= 1;
+            total_bytes -= size;
+        }
+    }
+}
+
+fn write_wav(path: &Path, samples: &[i16]) -> Result<(), hound::Error> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: TARGET_SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for &sample in samples {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()
+}
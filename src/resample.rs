@@ -0,0 +1,104 @@
+use std::f64::consts::PI;
+
+use crate::TARGET_SAMPLE_RATE;
+
+const ZERO_CROSSINGS: usize = 16; // sinc zero crossings on each side of the filter
+const KERNEL_RESOLUTION: usize = 128; // kernel table entries per input sample
+
+// Streaming windowed-sinc resampler (mono, f32)
+pub struct Resampler {
+    step: f64, // input samples per output sample
+    half_width: usize, // filter half width, in input samples
+    kernel: Vec<f64>, // one side of the (symmetric) filter
+    history: Vec<f32>, // input samples which are still needed by the filter
+    position: f64, // position of the next output sample in history
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Resampler {
+        // low-pass below the lower nyquist frequency to avoid aliasing when downsampling
+        let cutoff = (to_rate as f64 / from_rate as f64).min(1.0);
+        let half_width = (ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+        let kernel = (0..=half_width * KERNEL_RESOLUTION)
+            .map(|i| {
+                let x = i as f64 / KERNEL_RESOLUTION as f64;
+                let t = x * cutoff;
+                let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+                // Blackman window
+                let w = x / half_width as f64;
+                let window = 0.42 + 0.5 * (PI * w).cos() + 0.08 * (2.0 * PI * w).cos();
+                cutoff * sinc * window
+            })
+            .collect();
+        Resampler {
+            step: from_rate as f64 / to_rate as f64,
+            half_width,
+            kernel,
+            // start with silence so that the first output sample lines up with the first input sample
+            history: vec![0.0; half_width],
+            position: half_width as f64,
+        }
+    }
+
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.step == 1.0 {
+            output.extend_from_slice(input);
+            return;
+        }
+        self.history.extend_from_slice(input);
+        while self.position as usize + self.half_width < self.history.len() {
+            let center = self.position as usize;
+            let mut sum = 0.0;
+            for k in (center + 1 - self.half_width)..=(center + self.half_width) {
+                sum += self.history[k] as f64 * self.kernel_at(k as f64 - self.position);
+            }
+            output.push(sum as f32);
+            self.position += self.step;
+        }
+        // forget input which the filter won't reach again
+        let consumed = self.position as usize - self.half_width;
+        self.history.drain(..consumed);
+        self.position -= consumed as f64;
+    }
+
+    fn kernel_at(&self, x: f64) -> f64 {
+        // linear interpolation between kernel table entries
+        let index = x.abs() * KERNEL_RESOLUTION as f64;
+        let i = index as usize;
+        if i + 1 >= self.kernel.len() {
+            return 0.0;
+        }
+        let frac = index - i as f64;
+        self.kernel[i] + (self.kernel[i + 1] - self.kernel[i]) * frac
+    }
+}
+
+// Converts interleaved audio in any sample format into 16kHz mono
+pub struct AudioConverter {
+    channels: usize,
+    resampler: Resampler,
+    mono: Vec<f32>,
+    resampled: Vec<f32>,
+}
+
+impl AudioConverter {
+    pub fn new(sample_rate: u32, channels: u16) -> AudioConverter {
+        AudioConverter {
+            channels: (channels as usize).max(1),
+            resampler: Resampler::new(sample_rate, TARGET_SAMPLE_RATE),
+            mono: Vec::new(),
+            resampled: Vec::new(),
+        }
+    }
+
+    pub fn convert<T: cpal::Sample>(&mut self, interleaved: &[T], output: &mut Vec<i16>) {
+        // downmix by averaging the channels of each frame
+        self.mono.clear();
+        self.mono.extend(interleaved.chunks(self.channels)
+            .map(|frame| frame.iter().map(|s| s.to_f32()).sum::<f32>() / frame.len() as f32));
+        self.resampled.clear();
+        self.resampler.process(&self.mono, &mut self.resampled);
+        output.extend(self.resampled.iter()
+            .map(|&s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16));
+    }
+}
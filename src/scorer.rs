@@ -0,0 +1,79 @@
+use std::sync::Mutex;
+
+use crate::config::Config;
+use crate::recognizer::ISpeechRecognizer;
+
+pub const DEFAULT_SCORER: &str = "default"; // the `scorer` in Config
+
+// Scorer switch asked for by a CASL action, applied by the audio processing thread before its next stream
+struct ScorerRequest {
+    name: String,
+    utterances: Option<u32>, // switch back to the default scorer after this many streams
+}
+
+static SCORER_REQUEST: Mutex<Option<ScorerRequest>> = Mutex::new(None);
+
+pub fn request_scorer(name: &str, utterances: Option<u32>) {
+    *SCORER_REQUEST.lock().unwrap() = Some(ScorerRequest {
+        name: name.to_owned(),
+        utterances,
+    });
+}
+
+// Active scorer of the audio processing thread.
+// DeepSpeech can't change scorers while a stream is open, so this is only updated between streams.
+pub struct ScorerSwitch {
+    active: String,
+    remaining: Option<u32>, // streams left before switching back to the default scorer
+}
+
+impl ScorerSwitch {
+    pub fn new() -> ScorerSwitch {
+        ScorerSwitch {
+            active: DEFAULT_SCORER.to_owned(),
+            remaining: None,
+        }
+    }
+
+    // call right before creating a stream (while no stream is open)
+    pub fn update(&mut self, recognizer: &mut dyn ISpeechRecognizer, casl_config: &Config) {
+        let request = SCORER_REQUEST.lock().unwrap().take();
+        if let Some(request) = request {
+            self.switch(&request.name, recognizer, casl_config);
+            self.remaining = request.utterances;
+        } else if self.remaining == Some(0) {
+            self.switch(DEFAULT_SCORER, recognizer, casl_config);
+            self.remaining = None;
+        }
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining.saturating_sub(1);
+        }
+    }
+
+    fn switch(&mut self, name: &str, recognizer: &mut dyn ISpeechRecognizer, casl_config: &Config) {
+        if name == self.active {
+            return;
+        }
+        let path = if name == DEFAULT_SCORER {
+            casl_config.scorer.as_deref()
+        } else {
+            match casl_config.scorers.get(name) {
+                Some(path) => Some(path.as_str()),
+                None => {
+                    println!("Unknown scorer `{}`, still using scorer `{}`", name, &self.active);
+                    return;
+                }
+            }
+        };
+        if let Err(e) = recognizer.set_scorer(path) {
+            // the recognizer is left without a scorer
+            println!("Failed to switch to scorer `{}` ({}), using no scorer", name, e);
+            self.active = String::new();
+            return;
+        }
+        if casl_config.debug {
+            println!("Switched to scorer `{}`", name);
+        }
+        self.active = name.to_owned();
+    }
+}
@@ -0,0 +1,342 @@
+use crate::recognizer::TokenMetadata;
+use crate::speech::TIMESTEP_TO_MS;
+use crate::TARGET_SAMPLE_RATE;
+
+const SAMPLES_PER_MS: usize = (TARGET_SAMPLE_RATE / 1_000) as usize;
+
+// Where the speech and the last gap are in a transcript (times relative to the start of the stream)
+#[derive(Clone, Copy)]
+pub struct GapTimings {
+    pub speech_end_ms: u32, // end of the last token which isn't whitespace
+    pub gap_end_ms: u32, // start of the speech after the last gap (0 if there is no speech)
+    pub ends_with_gap: bool, // no speech in the last gap_detection_ms
+}
+
+pub fn gap_timings(tokens: &[TokenMetadata], length_ms: u32, gap_detection_ms: u32) -> GapTimings {
+    let mut text_start = 0;
+    let mut last_sound: u32 = 0;
+    let mut last_gap: u32 = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        // (timesteps should never go backwards, but a recognizer bug shouldn't crash CASL)
+        if token.timestep.saturating_sub(last_sound) * TIMESTEP_TO_MS > gap_detection_ms {
+            // only keep data after end of last recent gap
+            text_start = i;
+            last_gap = token.timestep;
+        }
+        if token.timestep > last_sound && !token.text.chars().all(char::is_whitespace) {
+            // keep track of when the last valid token was sent (the start of the gap)
+            last_sound = token.timestep;
+        }
+    }
+    // (without a gap, the silence before the first token doesn't need to be kept either)
+    let speech_start = tokens.get(text_start).map(|t| t.timestep).unwrap_or(last_gap);
+    GapTimings {
+        speech_end_ms: (last_sound + 1) * TIMESTEP_TO_MS,
+        // buffer zone, deepspeech is only accurate to ~20ms
+        gap_end_ms: speech_start.saturating_sub(1) * TIMESTEP_TO_MS,
+        ends_with_gap: length_ms.saturating_sub(last_sound * TIMESTEP_TO_MS) > gap_detection_ms,
+    }
+}
+
+// Part of a transcript between gaps (times relative to the start of the stream)
+#[derive(Clone, Copy)]
+pub struct UtteranceSpan {
+    pub start_ms: u32, // start of the first token which isn't whitespace
+    pub end_ms: u32, // end of the last token which isn't whitespace
+    // the part of the stream which belongs to this utterance, including the silence before it
+    pub from_ms: u32,
+    pub to_ms: u32,
+}
+
+// Split a transcript at every gap, using the same gaps as gap_timings.
+// There is always at least one span, which covers the whole stream when nothing was said.
+pub fn split_utterances(tokens: &[TokenMetadata], length_ms: u32, gap_detection_ms: u32) -> Vec<UtteranceSpan> {
+    let mut spans: Vec<UtteranceSpan> = Vec::new();
+    let mut last_sound: u32 = 0;
+    let mut is_new = true;
+    for token in tokens {
+        if token.timestep.saturating_sub(last_sound) * TIMESTEP_TO_MS > gap_detection_ms {
+            is_new = true;
+        }
+        if token.timestep >= last_sound && !token.text.chars().all(char::is_whitespace) {
+            last_sound = token.timestep;
+            let end_ms = (token.timestep + 1) * TIMESTEP_TO_MS;
+            match spans.last_mut() {
+                Some(span) if !is_new => span.end_ms = end_ms,
+                _ => spans.push(UtteranceSpan {
+                    // buffer zone, like gap_timings
+                    start_ms: token.timestep.saturating_sub(1) * TIMESTEP_TO_MS,
+                    end_ms,
+                    from_ms: 0,
+                    to_ms: 0,
+                }),
+            }
+            is_new = false;
+        }
+    }
+    if spans.is_empty() {
+        spans.push(UtteranceSpan { start_ms: 0, end_ms: 0, from_ms: 0, to_ms: 0 });
+    }
+    // silence before an utterance belongs to it, since its start is less accurate than its end
+    let count = spans.len();
+    for i in 0..count {
+        spans[i].from_ms = if i == 0 { 0 } else { spans[i - 1].to_ms };
+        spans[i].to_ms = if i + 1 == count { length_ms.max(spans[i].end_ms) } else { spans[i].end_ms }.max(spans[i].from_ms);
+    }
+    spans
+}
+
+// What happened to the buffer when it was refreshed
+pub enum Refresh {
+    Complete, // the transcript ends with a gap, so it can be handled
+    Incomplete, // still talking, so the speech after the last gap was carried over to be decoded again
+    TooLong, // still talking for longer than the buffer can hold, so the transcript must be handled now
+}
+
+// Audio buffer of the continuous decoding stream, which decides what to carry over into the next stream.
+// Invariants (checked in debug builds, and by the tests below):
+// - no lost samples: speech after the last gap is always carried over, unless the buffer is full
+// - no duplicated speech: speech in a handled transcript is never carried over
+// - bounded memory: the buffer never holds more than max_samples + refresh_threshold samples (plus one read)
+pub struct Segmenter {
+    buffer: Vec<i16>,
+    carryover_samples: usize,
+    refresh_threshold: usize,
+    max_samples: usize,
+    last_carryover: usize, // samples carried over at the last refresh
+    dropped: u64, // samples removed from the start of the buffer so far
+}
+
+impl Segmenter {
+    pub fn new(carryover_samples: usize, refresh_threshold: usize, max_samples: usize) -> Segmenter {
+        Segmenter {
+            buffer: Vec::with_capacity(refresh_threshold + carryover_samples),
+            carryover_samples,
+            refresh_threshold,
+            max_samples: max_samples.max(carryover_samples),
+            last_carryover: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, samples: &[i16]) {
+        self.buffer.extend_from_slice(samples);
+    }
+
+    // audio since the start of the current stream
+    pub fn buffer(&self) -> &[i16] {
+        &self.buffer
+    }
+
+    pub fn length_ms(&self) -> u32 {
+        (self.buffer.len() / SAMPLES_PER_MS) as u32
+    }
+
+    // time of the start of the buffer, since the first push
+    pub fn offset_ms(&self) -> u64 {
+        self.dropped / SAMPLES_PER_MS as u64
+    }
+
+    pub fn needs_refresh(&self) -> bool {
+        self.buffer.len() - self.last_carryover >= self.refresh_threshold
+    }
+
+    // remove audio which doesn't need to be decoded again, once the stream's final transcript is known
+    // (the new stream should then be fed the rest of the buffer)
+    pub fn refresh(&mut self, timings: &GapTimings) -> Refresh {
+        let len = self.buffer.len();
+        let speech_end = (timings.speech_end_ms as usize * SAMPLES_PER_MS).min(len);
+        let gap_end = (timings.gap_end_ms as usize * SAMPLES_PER_MS).min(len);
+        let (keep_from, refresh) = if timings.ends_with_gap {
+            // keep some audio in case speech has started without being decoded yet, but none of the handled speech
+            (len.saturating_sub(self.carryover_samples).max(speech_end), Refresh::Complete)
+        } else if len - gap_end <= self.max_samples {
+            (gap_end, Refresh::Incomplete)
+        } else {
+            // all of the speech is handled, so none of it can be carried over
+            (len, Refresh::TooLong)
+        };
+        debug_assert!(keep_from <= len);
+        debug_assert!(match refresh {
+            Refresh::Complete => keep_from >= speech_end && len - keep_from <= self.carryover_samples,
+            Refresh::Incomplete => keep_from <= gap_end && len - keep_from <= self.max_samples,
+            Refresh::TooLong => keep_from == len,
+        });
+        self.buffer.drain(..keep_from);
+        self.dropped += keep_from as u64;
+        self.last_carryover = self.buffer.len();
+        refresh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // property checks over random synthetic speech, for every seed:
+    // - every utterance is handled exactly once, as a whole utterance (no lost, duplicated or merged speech)
+    // - the buffer never grows past its limit (bounded memory)
+
+    #[test]
+    fn default_config() {
+        // casl.json
+        check(&Params { carryover: 8_096, threshold: 80_000, max: 240_000, gap_ms: 1_000, lag_ms: 100 });
+    }
+
+    #[test]
+    fn carryover_bigger_than_buffer() {
+        // (used to underflow)
+        check(&Params { carryover: 100_000, threshold: 16_000, max: 48_000, gap_ms: 500, lag_ms: 100 });
+    }
+
+    #[test]
+    fn refresh_more_often_than_utterances() {
+        check(&Params { carryover: 4_000, threshold: 4_000, max: 160_000, gap_ms: 300, lag_ms: 0 });
+    }
+
+    #[test]
+    fn tiny_carryover() {
+        // (only works without decoder lag)
+        check(&Params { carryover: 320, threshold: 32_000, max: 64_000, gap_ms: 800, lag_ms: 0 });
+    }
+
+    fn check(params: &Params) {
+        let failures: Vec<String> = (1..=20u64)
+            .filter_map(|seed| run(params, seed).err().map(|e| format!("seed {}: {}", seed, e)))
+            .collect();
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    const SAMPLES_PER_TIMESTEP: usize = 320;
+    const TOTAL_MS: usize = 30 * 60 * 1_000; // of audio per run
+    const MAX_READ: usize = 4_096; // samples
+
+    struct Params {
+        carryover: usize,
+        threshold: usize,
+        max: usize,
+        gap_ms: u32,
+        lag_ms: usize, // the newest audio has no tokens yet, like a real decoder
+    }
+
+    #[derive(Clone, Copy)]
+    struct Utterance {
+        start: usize, // absolute samples
+        end: usize,
+    }
+
+    fn run(params: &Params, seed: u64) -> Result<(), String> {
+        let mut rng = Rng(seed);
+        let utterances = generate(params, &mut rng);
+        let mut handled = vec![0u32; utterances.len()];
+        let mut segmenter = Segmenter::new(params.carryover, params.threshold, params.max);
+        let limit = params.max.max(params.carryover) + params.threshold + MAX_READ;
+        let total = TOTAL_MS * SAMPLES_PER_MS;
+        let mut pushed = 0;
+        while pushed < total {
+            let read = (rng.below(MAX_READ as u64) as usize + 1).min(total - pushed);
+            segmenter.push(&vec![0; read]);
+            pushed += read;
+            if segmenter.buffer().len() > limit {
+                return Err(format!("buffer grew to {} samples (limit {})", segmenter.buffer().len(), limit));
+            }
+            if !segmenter.needs_refresh() {
+                continue;
+            }
+            let window_start = pushed - segmenter.buffer().len();
+            if window_start / SAMPLES_PER_MS != segmenter.offset_ms() as usize {
+                return Err(format!("buffer offset {}ms is wrong (should be {}ms)", segmenter.offset_ms(), window_start / SAMPLES_PER_MS));
+            }
+            let tokens = tokens(&utterances, window_start, pushed - params.lag_ms * SAMPLES_PER_MS);
+            let timings = gap_timings(&tokens, segmenter.length_ms(), params.gap_ms);
+            let spans = split_utterances(&tokens, segmenter.length_ms(), params.gap_ms);
+            let complete = match segmenter.refresh(&timings) {
+                Refresh::Complete | Refresh::TooLong => spans.len(),
+                Refresh::Incomplete => spans.len() - 1, // (like the continuous decoding loop)
+            };
+            handle(&spans[..complete], window_start, &utterances, &mut handled)?;
+        }
+        // the rest of the buffer is decoded once the input ends
+        let window_start = pushed - segmenter.buffer().len();
+        let tokens = tokens(&utterances, window_start, pushed);
+        handle(&split_utterances(&tokens, segmenter.length_ms(), params.gap_ms), window_start, &utterances, &mut handled)?;
+        let max = params.max.max(params.carryover); // (like Segmenter::new)
+        for (i, u) in utterances.iter().enumerate() {
+            let is_too_long = u.end - u.start > max;
+            match handled[i] {
+                1 => {},
+                0 if is_too_long || u.end > total => {}, // split up because the buffer was full, or never finished
+                n => return Err(format!("utterance {}..{}ms handled {} times", u.start / SAMPLES_PER_MS, u.end / SAMPLES_PER_MS, n)),
+            }
+        }
+        Ok(())
+    }
+
+    // count the utterances which were dispatched as a whole
+    fn handle(spans: &[UtteranceSpan], window_start: usize, utterances: &[Utterance], handled: &mut [u32]) -> Result<(), String> {
+        for span in spans.iter().filter(|s| s.end_ms != 0) {
+            let start = window_start + span.start_ms as usize * SAMPLES_PER_MS;
+            let end = window_start + span.end_ms as usize * SAMPLES_PER_MS;
+            let heard: Vec<usize> = (0..utterances.len())
+                .filter(|&i| utterances[i].start < end && utterances[i].end > start)
+                .collect();
+            if heard.len() > 1 {
+                return Err(format!("utterances merged into one at {}..{}ms", start / SAMPLES_PER_MS, end / SAMPLES_PER_MS));
+            }
+            for i in heard {
+                if utterances[i].start >= start && utterances[i].end <= end {
+                    handled[i] += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // speech separated by gaps which are long enough to be detected
+    fn generate(params: &Params, rng: &mut Rng) -> Vec<Utterance> {
+        let mut utterances = Vec::new();
+        let mut ms = rng.below(3_000) as usize;
+        while ms < TOTAL_MS {
+            let length_ms = if rng.below(20) == 0 {
+                (params.max / SAMPLES_PER_MS) + rng.below(10_000) as usize // too long for the buffer
+            } else {
+                200 + rng.below(4_000) as usize
+            };
+            utterances.push(Utterance {
+                start: ms * SAMPLES_PER_MS,
+                end: (ms + length_ms) * SAMPLES_PER_MS,
+            });
+            ms += length_ms + params.gap_ms as usize + 100 + rng.below(6_000) as usize;
+        }
+        utterances
+    }
+
+    // one token per timestep of speech in the buffer, up to what the decoder has caught up with
+    fn tokens(utterances: &[Utterance], window_start: usize, decoded_end: usize) -> Vec<TokenMetadata> {
+        let mut tokens = Vec::new();
+        for u in utterances.iter().filter(|u| u.end > window_start && u.start < decoded_end) {
+            let first = (u.start.max(window_start) - window_start).div_ceil(SAMPLES_PER_TIMESTEP);
+            let mut timestep = first;
+            while window_start + timestep * SAMPLES_PER_TIMESTEP < u.end.min(decoded_end) {
+                if timestep != first && timestep.is_multiple_of(5) {
+                    tokens.push(TokenMetadata { text: " ".to_owned(), timestep: timestep as u32 });
+                }
+                tokens.push(TokenMetadata { text: "a".to_owned(), timestep: timestep as u32 });
+                timestep += 1;
+            }
+        }
+        tokens
+    }
+
+    // xorshift, so that failures can be reproduced from the seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+}
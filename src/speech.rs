@@ -1,123 +1,588 @@
-use std::sync::mpsc::{Receiver, Sender};
-use deepspeech::{Model, Stream};
+use std::ops::Range;
+use std::sync::mpsc::Receiver;
+use std::thread::JoinHandle;
+use serde::Serialize;
 
 use crate::{config, TARGET_SAMPLE_RATE};
+use crate::recognizer::{ISpeechRecognizer, IRecognitionStream, Metadata, CandidateTranscript};
+use crate::resample::AudioConverter;
+use crate::audio_buffer::{AudioReceiver, AudioSender};
+use crate::vad::{VadConfig, VadEvent, VoiceActivityDetector};
+use crate::wake::IWakeDetector;
+use crate::recorder::Recorder;
+use crate::partial::{PartialDecoder, PartialEvent};
+use crate::scorer::ScorerSwitch;
+use crate::pipeline::Pipeline;
+use crate::command_api::Word;
+use crate::segmenter::{gap_timings, split_utterances, Refresh, Segmenter, UtteranceSpan};
 
-const TIMESTEP_TO_MS: u32 = 20; // 20ms increments
+pub const TIMESTEP_TO_MS: u32 = 20; // 20ms increments
 
-pub fn process_audio_loop(cntrl: Receiver<bool>, audio: Receiver<i16>, casl_config: &config::Config) {
+pub fn process_audio_loop(cntrl: Receiver<bool>, mut audio: AudioReceiver, casl_config: &config::Config, pipeline: &Pipeline) {
+    if let Some(vad_config) = &casl_config.vad {
+        return process_audio_vad_loop(cntrl, audio, vad_config, casl_config, pipeline);
+    }
     // init
-    let mut speech2text = Model::load_from_files(std::path::Path::new(&casl_config.model)).unwrap();
-    if let Some(scorer) = &casl_config.scorer {
-        speech2text.enable_external_scorer(std::path::Path::new(scorer)).unwrap();
+    let mut speech2text = casl_config.recognizer();
+    let mut scorer = ScorerSwitch::new();
+    scorer.update(speech2text.as_mut(), casl_config);
+    let mut stream = speech2text.create_stream();
+    let mut wake_detector = casl_config.wake.as_ref().and_then(|w| w.detector());
+    let mut dispatcher = Dispatcher::new(casl_config);
+    let mut segmenter = Segmenter::new(casl_config.carryover_buffer_size,
+                                       casl_config.refresh_buffer_threshold,
+                                       casl_config.max_buffer_size());
+    let mut block = std::vec::Vec::with_capacity(casl_config.carryover_buffer_size);
+    let mut is_exiting = cntrl.try_recv().unwrap_or(false);
+    if casl_config.debug {
+        println!("Audio processing thread ready");
+    }
+    while !is_exiting {
+        // process audio until exit signal is received
+        block.clear();
+        let is_connected = process_audio(&mut audio, stream.as_mut(), &mut wake_detector, &mut block, casl_config.carryover_buffer_size);
+        report_overflow(&audio);
+        segmenter.push(&block);
+        if let Some(p) = &mut dispatcher.partial {
+            let event = p.update(stream.as_mut(), block.len(), segmenter.length_ms(), segmenter.offset_ms(), casl_config, pipeline);
+            dispatcher.handle_partial(event, casl_config, pipeline);
+        }
+        if !is_connected {
+            // input source has no more audio, so decode whatever is left and stop
+            let text = stream.finish_with_metadata(casl_config.candidates);
+            let utterances = process_utterances(&text, segmenter.length_ms(), segmenter.offset_ms(), casl_config, pipeline);
+            dispatcher.dispatch_utterances(&utterances, segmenter.buffer(), casl_config, pipeline);
+            if casl_config.debug {
+                println!("Audio input ended");
+            }
+            // (CASL exits once this returns)
+            dispatcher.wait_before_exit(casl_config);
+            return;
+        }
+        if segmenter.needs_refresh() {
+            // decode audio and refresh stream
+            stream = next_stream(stream, speech2text.as_mut(), &mut scorer, &mut segmenter, &mut dispatcher, casl_config, pipeline);
+            if casl_config.debug {
+                println!("Stream buffer refreshed (now: {} samples)", segmenter.buffer().len());
+            }
+        }
+        is_exiting = cntrl.try_recv().unwrap_or(false);
+    }
+}
+
+// finish the stream and remove the handled audio from the buffer,
+// returning the utterances which are complete (and the buffer they are in, from before the refresh)
+fn refresh_stream(stream: Box<dyn IRecognitionStream>, segmenter: &mut Segmenter, casl_config: &config::Config, pipeline: &Pipeline) -> (Vec<Utterance>, Vec<i16>) {
+    let text = stream.finish_with_metadata(casl_config.candidates);
+    let mut utterances = process_utterances(&text, segmenter.length_ms(), segmenter.offset_ms(), casl_config, pipeline);
+    let tokens = text.transcripts.first().map(|t| &t.tokens[..]).unwrap_or(&[]);
+    let timings = gap_timings(tokens, segmenter.length_ms(), casl_config.gap_detection_ms as u32);
+    let buffer = segmenter.buffer().to_vec();
+    match segmenter.refresh(&timings) {
+        Refresh::Complete => {},
+        Refresh::Incomplete => {
+            // the utterance still being spoken is decoded again with the carried over audio
+            utterances.pop();
+            if casl_config.debug {
+                println!("Stream buffer refreshed while still talking, carrying over extra samples ({})", segmenter.buffer().len());
+            }
+        },
+        Refresh::TooLong => {
+            if casl_config.debug {
+                println!("Stream buffer is full while still talking, handling the transcript so far");
+            }
+        },
+    }
+    (utterances, buffer)
+}
+
+// finish the stream and run commands for it, then start the next stream with the carried over audio
+// (commands run before the scorer is updated, so that a scorer they ask for is used for the speech right after them)
+fn next_stream(stream: Box<dyn IRecognitionStream>, speech2text: &mut dyn ISpeechRecognizer, scorer: &mut ScorerSwitch, segmenter: &mut Segmenter, dispatcher: &mut Dispatcher, casl_config: &config::Config, pipeline: &Pipeline) -> Box<dyn IRecognitionStream> {
+    let (utterances, buffer) = refresh_stream(stream, segmenter, casl_config, pipeline);
+    dispatcher.dispatch_utterances(&utterances, &buffer, casl_config, pipeline);
+    // (the old stream is finished, so the scorer can be switched)
+    scorer.update(speech2text, casl_config);
+    let mut stream = speech2text.create_stream();
+    if let Some(p) = &mut dispatcher.partial {
+        p.reset();
     }
-    let mut stream = speech2text.create_stream().unwrap();
+    stream.feed_audio(segmenter.buffer());
+    stream
+}
+
+// decode only while speech is detected, with one stream per utterance
+fn process_audio_vad_loop(cntrl: Receiver<bool>, mut audio: AudioReceiver, vad_config: &VadConfig, casl_config: &config::Config, pipeline: &Pipeline) {
+    // init
+    let mut speech2text = casl_config.recognizer();
+    let mut scorer = ScorerSwitch::new();
+    let mut vad = VoiceActivityDetector::new(vad_config);
+    let lookback = vad.lookback_samples();
+    let mut stream: Option<Box<dyn IRecognitionStream>> = None;
+    let mut wake_detector = casl_config.wake.as_ref().and_then(|w| w.detector());
+    let mut dispatcher = Dispatcher::new(casl_config);
+    // the utterance so far while speaking, recent audio otherwise
     let mut buffer = std::vec::Vec::with_capacity(casl_config.refresh_buffer_threshold);
+    let mut block = std::vec::Vec::with_capacity(casl_config.carryover_buffer_size);
+    let mut received: u64 = 0; // samples since audio input started
     let mut is_exiting = cntrl.try_recv().unwrap_or(false);
-    let mut last_carryover = 0;
     if casl_config.debug {
-        println!("Audio processing thread ready");
+        println!("Audio processing thread ready (with voice activity detection)");
     }
     while !is_exiting {
         // process audio until exit signal is received
-        process_audio(&audio, &mut stream, &mut buffer, casl_config.carryover_buffer_size);
-        if buffer.len() - last_carryover >= casl_config.refresh_buffer_threshold {
-            // decode audio
-            let text = stream.finish_with_metadata(1).unwrap();
-            let meta = process_metadata(&text,
-                                        (buffer.len() as u32 / (TARGET_SAMPLE_RATE / 1_000)) as u32,
-                                        casl_config);
-            // refresh stream
-            stream = speech2text.create_stream().unwrap();
-            if meta.safe_to_refresh {
-                crate::command::process_commands(&meta, casl_config);
-                //println!("Handling: `{}` -> `{}`", meta.phrase_raw, meta.phrase);
-                let mut carryover = std::vec::Vec::with_capacity(casl_config.carryover_buffer_size);
-                carryover.extend(&buffer[buffer.len()-casl_config.carryover_buffer_size..]);
-                buffer = std::vec::Vec::with_capacity(casl_config.refresh_buffer_threshold);
-                buffer.extend(&carryover);
-            } else {
-                let gap_start_sample = (meta.last_gap_end_ms * TARGET_SAMPLE_RATE / 1_000) as usize;
-                let mut carryover = std::vec::Vec::with_capacity(buffer.len()-gap_start_sample);
-                carryover.extend(&buffer[buffer.len()-gap_start_sample..]);
-                buffer = std::vec::Vec::with_capacity(casl_config.refresh_buffer_threshold);
-                buffer.extend(&carryover);
-                if casl_config.debug {
-                    println!("Stream buffer refreshed while still talking, carrying over extra samples ({})", buffer.len());
+        block.clear();
+        let is_connected = audio.recv_block(&mut block, casl_config.carryover_buffer_size).is_ok();
+        report_overflow(&audio);
+        if let Some(detector) = &mut wake_detector {
+            detector.feed(&block);
+        }
+        let block_start = received;
+        received += block.len() as u64;
+        let mut start = 0;
+        for (end, event) in vad.process(&block) {
+            append_audio(&block[start..end], &mut stream, &mut buffer, lookback);
+            start = end;
+            match event {
+                VadEvent::SpeechStart => {
+                    scorer.update(speech2text.as_mut(), casl_config);
+                    let mut new_stream = speech2text.create_stream();
+                    new_stream.feed_audio(&buffer);
+                    stream = Some(new_stream);
+                    if let Some(p) = &mut dispatcher.partial {
+                        p.reset();
+                    }
+                    if casl_config.debug {
+                        println!("Speech started");
+                    }
+                },
+                VadEvent::SpeechEnd => {
+                    if let Some(s) = stream.take() {
+                        let offset_ms = samples_to_ms_u64(block_start + end as u64 - buffer.len() as u64);
+                        decode_utterance(s, &buffer, offset_ms, &mut dispatcher, casl_config, pipeline);
+                    }
+                    buffer.clear();
                 }
             }
-            last_carryover = buffer.len();
-            stream.feed_audio(&buffer);
+        }
+        append_audio(&block[start..], &mut stream, &mut buffer, lookback);
+        if let (Some(s), Some(p)) = (&mut stream, &mut dispatcher.partial) {
+            let offset_ms = samples_to_ms_u64(received - buffer.len() as u64);
+            let event = p.update(s.as_mut(), block.len(), samples_to_ms(buffer.len()), offset_ms, casl_config, pipeline);
+            dispatcher.handle_partial(event, casl_config, pipeline);
+        }
+        if !is_connected {
+            // input source has no more audio, so decode whatever is left and stop
+            if let Some(s) = stream.take() {
+                let offset_ms = samples_to_ms_u64(received - buffer.len() as u64);
+                decode_utterance(s, &buffer, offset_ms, &mut dispatcher, casl_config, pipeline);
+            }
             if casl_config.debug {
-                println!("Stream buffer refreshed (now: {} samples)", buffer.len());
+                println!("Audio input ended");
             }
+            // (CASL exits once this returns)
+            dispatcher.wait_before_exit(casl_config);
+            return;
         }
         is_exiting = cntrl.try_recv().unwrap_or(false);
     }
 }
 
-pub fn process_audio(sample_rx: &Receiver<i16>, stream: &mut Stream, buffer: &mut Vec<i16>, max: usize) {
-    let mut count = 0;
-    let mut sample = sample_rx.recv();
-    let start = buffer.len();
-    while sample.is_ok() && count < max {
-        //println!("Sample: {}", sample.unwrap());
-        buffer.push(sample.unwrap());
-        sample = sample_rx.recv();
-        count+=1;
+fn append_audio(samples: &[i16], stream: &mut Option<Box<dyn IRecognitionStream>>, buffer: &mut Vec<i16>, lookback: usize) {
+    buffer.extend_from_slice(samples);
+    match stream {
+        Some(s) => s.feed_audio(samples),
+        None => {
+            // not speaking, so only keep enough audio to catch the start of the next utterance
+            if buffer.len() > lookback {
+                buffer.drain(..buffer.len()-lookback);
+            }
+        }
     }
-    stream.feed_audio(&buffer[start..]);
 }
 
-pub fn process_metadata(metadata: &deepspeech::Metadata, length_ms: u32, casl_config: &config::Config) -> MetadataResult {
-    let mut text = String::new();
-    let transcript = &metadata.transcripts()[0];
-    // TODO detect gaps to use as start of sentence
-    let mut last_sound: u32 = 0;
-    let mut last_gap: u32 = 0;
-    for token in transcript.tokens() {
-        if (token.timestep() - last_sound) * TIMESTEP_TO_MS > casl_config.gap_detection_ms as u32 {
-            // clear text if gap detected (only keep data after end of last recent gap)
-            text.clear();
-            last_gap = token.timestep();
+// (the speech detector can miss a short gap, so there may be more than one utterance)
+fn decode_utterance(stream: Box<dyn IRecognitionStream>, buffer: &[i16], offset_ms: u64, dispatcher: &mut Dispatcher, casl_config: &config::Config, pipeline: &Pipeline) {
+    let text = stream.finish_with_metadata(casl_config.candidates);
+    let utterances = process_utterances(&text, samples_to_ms(buffer.len()), offset_ms, casl_config, pipeline);
+    dispatcher.dispatch_utterances(&utterances, buffer, casl_config, pipeline);
+}
+
+// Runs commands for decoded utterances (and records them) for the audio processing thread
+struct Dispatcher {
+    partial: Option<PartialDecoder>,
+    recorder: Option<Recorder>,
+    pending: Vec<JoinHandle<()>>, // commands running in the background
+}
+
+impl Dispatcher {
+    fn new(casl_config: &config::Config) -> Dispatcher {
+        Dispatcher {
+            partial: casl_config.partial_decoder(),
+            recorder: casl_config.recorder.as_ref().map(Recorder::new),
+            pending: Vec::new(),
         }
-        text += token.text().unwrap();
-        if token.timestep() > last_sound && !token.text().unwrap().chars().all(char::is_whitespace) {
-            // keep track of when the last valid token was sent (the start of the gap)
-            last_sound = token.timestep();
+    }
+
+    // run commands for each utterance in the order they were spoken
+    fn dispatch_utterances(&mut self, utterances: &[Utterance], buffer: &[i16], casl_config: &config::Config, pipeline: &Pipeline) {
+        for utterance in utterances {
+            let samples = &buffer[utterance.samples.start.min(buffer.len())..utterance.samples.end.min(buffer.len())];
+            self.dispatch_commands(&utterance.result, samples, casl_config, pipeline);
         }
     }
-    let ends_with_gap: bool = length_ms - (last_sound * TIMESTEP_TO_MS) > casl_config.gap_detection_ms as u32;
-    // preprocess text
-    let mut processed_text = text.clone();
-    for pre in &casl_config.preprocessors {
-        processed_text = pre.preprocessor().process(&processed_text).to_string();
+
+    // run commands for the final transcript of the audio in buffer
+    fn dispatch_commands(&mut self, meta: &MetadataResult, buffer: &[i16], casl_config: &config::Config, pipeline: &Pipeline) {
+        let dispatch = match &mut self.partial {
+            Some(p) => crate::command::process_final_commands(meta, &mut p.eager, casl_config, pipeline),
+            None => crate::command::process_commands(meta, casl_config, pipeline),
+        };
+        if let Some(r) = &mut self.recorder {
+            r.record(buffer, meta, &dispatch.matched);
+        }
+        self.keep_running(dispatch.threads);
     }
-    if last_gap != 0 { last_gap -= 1; } // buffer zone, deepspeech is only accurate to ~20ms
-    MetadataResult {
-        safe_to_refresh: ends_with_gap,
-        phrase_raw: text,
-        phrase: processed_text,
-        last_gap_start_ms: (last_sound+1) * TIMESTEP_TO_MS,
-        last_gap_end_ms: last_gap * TIMESTEP_TO_MS,
+
+    fn handle_partial(&mut self, event: Option<PartialEvent>, casl_config: &config::Config, pipeline: &Pipeline) {
+        match (event, &mut self.partial) {
+            (Some(PartialEvent::Changed(meta)), _) if casl_config.debug && !meta.phrase_raw.is_empty() => {
+                println!("Partial `{}`", meta.phrase_raw);
+            },
+            (Some(PartialEvent::Stable(meta)), Some(p)) => {
+                let dispatch = crate::command::process_partial_commands(&meta, &mut p.eager, casl_config, pipeline);
+                self.keep_running(dispatch.threads);
+            },
+            _ => {}
+        }
     }
+
+    // remember commands running in the background (forgetting those which are done)
+    fn keep_running(&mut self, threads: Vec<JoinHandle<()>>) {
+        self.pending.retain(|t| !t.is_finished());
+        self.pending.extend(threads);
+    }
+
+    fn wait_before_exit(self, casl_config: &config::Config) {
+        crate::command::wait_before_exit(self.pending, casl_config);
+    }
+}
+
+fn samples_to_ms(samples: usize) -> u32 {
+    samples as u32 / (TARGET_SAMPLE_RATE / 1_000)
 }
 
-pub fn capture_audio(data: &[f32], _: &cpal::InputCallbackInfo, audio_tx: Sender<i16>) {
-    for &sample in data {
-        let int_sample: i16 = (sample*((std::i16::MAX) as f32)) as i16;
-        //println!("{} f32 -> {} i16", sample, int_sample);
-        audio_tx.send(int_sample).unwrap_or(()); // ignore errors
+fn samples_to_ms_u64(samples: u64) -> u64 {
+    samples / (TARGET_SAMPLE_RATE / 1_000) as u64
+}
+
+fn ms_to_samples(ms: u32) -> usize {
+    ms as usize * (TARGET_SAMPLE_RATE / 1_000) as usize
+}
+
+fn report_overflow(audio: &AudioReceiver) {
+    let overflow = audio.take_overflow();
+    if overflow != 0 {
+        println!("Audio processing is falling behind, dropped {} samples", overflow);
+    }
+}
+
+// returns false once the audio source has disconnected
+pub fn process_audio(audio: &mut AudioReceiver, stream: &mut dyn IRecognitionStream, wake_detector: &mut Option<Box<dyn IWakeDetector>>, block: &mut Vec<i16>, max: usize) -> bool {
+    let is_connected = audio.recv_block(block, max).is_ok();
+    stream.feed_audio(block);
+    if let Some(detector) = wake_detector {
+        detector.feed(block);
     }
+    is_connected
+}
+
+// One utterance of a decoded stream
+pub struct Utterance {
+    pub result: MetadataResult,
+    pub samples: Range<usize>, // its audio in the stream's buffer
+}
+
+// split the transcript of a stream into utterances at every gap, in the order they were spoken
+// offset_ms is the time of the start of the stream, since audio input started
+pub fn process_utterances(metadata: &Metadata, length_ms: u32, offset_ms: u64, casl_config: &config::Config, pipeline: &Pipeline) -> Vec<Utterance> {
+    // (a recognizer which returns no transcripts heard nothing)
+    let nothing = CandidateTranscript {
+        confidence: 0.0,
+        tokens: Vec::new(),
+    };
+    let transcript = metadata.transcripts.first().unwrap_or(&nothing);
+    let timings = gap_timings(&transcript.tokens, length_ms, casl_config.gap_detection_ms as u32);
+    let spans = split_utterances(&transcript.tokens, length_ms, casl_config.gap_detection_ms as u32);
+    let count = spans.len();
+    spans.iter().enumerate().map(|(i, span)| {
+        let text = span_text(transcript, span);
+        let result = MetadataResult {
+            words: span_words(transcript, span, offset_ms),
+            // (only the last utterance can still be being spoken)
+            safe_to_refresh: i + 1 < count || timings.ends_with_gap,
+            phrase: pipeline.preprocess(&text),
+            phrase_raw: text,
+            confidence: transcript.confidence,
+            // other candidates are split at the same times, since their gaps may be different
+            alternates: metadata.transcripts.iter().skip(1).map(|t| {
+                let text = span_text(t, span);
+                CandidateResult {
+                    words: span_words(t, span, offset_ms),
+                    phrase: pipeline.preprocess(&text),
+                    phrase_raw: text,
+                    confidence: t.confidence,
+                }
+            }).collect(),
+            start_ms: offset_ms + span.start_ms as u64,
+            end_ms: offset_ms + span.end_ms as u64,
+            last_gap_start_ms: timings.speech_end_ms,
+            last_gap_end_ms: timings.gap_end_ms,
+        };
+        Utterance {
+            result,
+            samples: ms_to_samples(span.from_ms)..ms_to_samples(span.to_ms),
+        }
+    }).collect()
 }
 
-pub fn capture_error(error: cpal::StreamError) { println!("Stream error {:?}", error); }
+// the utterance being spoken (after the last gap)
+pub fn process_metadata(metadata: &Metadata, length_ms: u32, offset_ms: u64, casl_config: &config::Config, pipeline: &Pipeline) -> MetadataResult {
+    process_utterances(metadata, length_ms, offset_ms, casl_config, pipeline).pop()
+        .expect("Transcript split into no utterances")
+        .result
+}
 
-#[derive(Clone)]
+fn span_text(transcript: &CandidateTranscript, span: &UtteranceSpan) -> String {
+    transcript.tokens.iter()
+        .filter(|t| t.timestep * TIMESTEP_TO_MS >= span.from_ms && t.timestep * TIMESTEP_TO_MS < span.to_ms)
+        .map(|t| t.text.as_str())
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
+// words (split at whitespace tokens) in the same part of the transcript as span_text
+fn span_words(transcript: &CandidateTranscript, span: &UtteranceSpan, offset_ms: u64) -> Vec<Word> {
+    let mut words: Vec<Word> = Vec::new();
+    let mut is_new = true;
+    for token in transcript.tokens.iter().filter(|t| t.timestep * TIMESTEP_TO_MS >= span.from_ms && t.timestep * TIMESTEP_TO_MS < span.to_ms) {
+        if token.text.chars().all(char::is_whitespace) {
+            is_new = true;
+            continue;
+        }
+        let start_ms = offset_ms + (token.timestep * TIMESTEP_TO_MS) as u64;
+        let end_ms = start_ms + TIMESTEP_TO_MS as u64;
+        match words.last_mut() {
+            Some(word) if !is_new => {
+                word.text.push_str(&token.text);
+                word.end_ms = end_ms;
+            },
+            _ => words.push(Word {
+                text: token.text.clone(),
+                start_ms,
+                end_ms,
+                confidence: transcript.confidence,
+            }),
+        }
+        is_new = false;
+    }
+    words
+}
+
+// treat typed text as if it was heard as a complete phrase
+pub fn process_text(text: &str, pipeline: &Pipeline) -> MetadataResult {
+    MetadataResult {
+        safe_to_refresh: true,
+        phrase_raw: text.to_owned(),
+        phrase: pipeline.preprocess(text),
+        confidence: 0.0,
+        alternates: Vec::new(),
+        words: text.split_whitespace().map(|w| Word {
+            text: w.to_owned(),
+            start_ms: 0,
+            end_ms: 0,
+            confidence: 0.0,
+        }).collect(),
+        start_ms: 0,
+        end_ms: 0,
+        last_gap_start_ms: 0,
+        last_gap_end_ms: 0,
+    }
+}
+
+pub fn capture_audio<T: cpal::Sample>(data: &[T], converter: &mut AudioConverter, samples: &mut Vec<i16>, audio_tx: &mut AudioSender) {
+    samples.clear();
+    converter.convert(data, samples);
+    audio_tx.send_block(samples); // ignore errors
+}
+
+#[derive(Serialize, Clone)]
 pub struct MetadataResult {
     pub safe_to_refresh: bool,
     pub phrase_raw: String,
     pub phrase: String,
+    pub confidence: f64,
+    pub alternates: Vec<CandidateResult>, // other candidate transcripts
+    pub words: Vec<Word>, // of phrase_raw
+    // when the utterance was spoken, since audio input started (0 for typed text)
+    pub start_ms: u64,
+    pub end_ms: u64,
     pub last_gap_start_ms: u32,
     pub last_gap_end_ms: u32
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Clone)]
+pub struct CandidateResult {
+    pub phrase_raw: String,
+    pub phrase: String,
+    pub confidence: f64,
+    pub words: Vec<Word>,
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 1_600; // 100ms
+
+    // (phrase, start_ms, end_ms) of every utterance the continuous decoding loop would dispatch for `blocks` of audio
+    fn dispatched(casl_config: &config::Config, blocks: usize) -> Vec<(String, u64, u64)> {
+        let pipeline = Pipeline::new(casl_config).expect("Invalid test pipeline");
+        let mut recognizer = casl_config.recognizer();
+        let mut stream = recognizer.create_stream();
+        let mut segmenter = Segmenter::new(casl_config.carryover_buffer_size,
+                                           casl_config.refresh_buffer_threshold,
+                                           casl_config.max_buffer_size());
+        let mut result = Vec::new();
+        for _ in 0..blocks {
+            let block = vec![0i16; BLOCK];
+            stream.feed_audio(&block);
+            segmenter.push(&block);
+            if segmenter.needs_refresh() {
+                let (utterances, _) = refresh_stream(stream, &mut segmenter, casl_config, &pipeline);
+                result.extend(utterances.iter().map(|u| (u.result.phrase.clone(), u.result.start_ms, u.result.end_ms)));
+                stream = recognizer.create_stream();
+                stream.feed_audio(segmenter.buffer());
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn dispatches_every_utterance_once_in_order() {
+        let casl_config = config::Config::mock(r#"[
+            {"phrases": [{"text": "start steam", "start_ms": 0}, {"text": "hello world", "start_ms": 3000}]},
+            {"phrases": [{"text": "open the browser", "start_ms": 2000}]},
+            {"phrases": [{"text": "play music", "start_ms": 5000}]},
+            {"phrases": [{"text": "play music", "start_ms": 40}]}
+        ]"#);
+        // streams refresh every 5000ms of new audio, and 8096 samples (506ms) are carried over after a gap
+        assert_eq!(dispatched(&casl_config, 200), vec![
+            // (start is one timestep early, since deepspeech is only accurate to ~20ms)
+            ("start steam".to_owned(), 0, 220),
+            ("hello world".to_owned(), 2_980, 3_220),
+            // the second stream starts at 5000ms - 506ms
+            ("open the browser".to_owned(), 4_494 + 1_980, 4_494 + 2_320),
+            // still talking when the third stream was refreshed, so it's only dispatched from the fourth,
+            // which starts at the end of the gap before it (4980ms into the third stream, which starts at 10000ms - 506ms)
+            ("play music".to_owned(), 9_494 + 4_980 + 20, 9_494 + 4_980 + 240),
+        ]);
+    }
+
+    // Mock recognizer which remembers the scorer of every stream it creates
+    struct ScorerLog {
+        recognizer: Box<dyn ISpeechRecognizer>,
+        scorer: Option<String>,
+        streams: Vec<Option<String>>,
+    }
+
+    impl ISpeechRecognizer for ScorerLog {
+        fn create_stream(&mut self) -> Box<dyn IRecognitionStream> {
+            self.streams.push(self.scorer.clone());
+            self.recognizer.create_stream()
+        }
+
+        fn set_scorer(&mut self, path: Option<&str>) -> Result<(), String> {
+            self.scorer = path.map(|p| p.to_owned());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn switches_scorer_for_the_stream_after_the_request() {
+        let mut casl_config = config::Config::mock(r#"[
+            {"phrases": [{"text": "spell", "start_ms": 1000}]},
+            {"phrases": [{"text": "alpha bravo", "start_ms": 1000}]},
+            {"phrases": [{"text": "hello", "start_ms": 1000}]}
+        ]"#);
+        casl_config.scorer = Some("general.scorer".to_owned());
+        casl_config.scorers.insert("spelling".to_owned(), "nato.scorer".to_owned());
+        casl_config.commands = serde_json::from_str(r#"[{
+            "type": "Action",
+            "precondition": "^spell$",
+            "use_raw_text": true,
+            "action": {"type": "CASL", "operation": "scorer", "parameters": ["spelling", "1"]}
+        }]"#).expect("Invalid test commands");
+        let pipeline = Pipeline::new(&casl_config).expect("Invalid test pipeline");
+        let mut recognizer = ScorerLog {
+            recognizer: casl_config.recognizer(),
+            scorer: casl_config.scorer.clone(),
+            streams: Vec::new(),
+        };
+        let mut scorer = ScorerSwitch::new();
+        let mut dispatcher = Dispatcher::new(&casl_config);
+        scorer.update(&mut recognizer, &casl_config);
+        let mut stream = recognizer.create_stream();
+        let mut segmenter = Segmenter::new(casl_config.carryover_buffer_size,
+                                           casl_config.refresh_buffer_threshold,
+                                           casl_config.max_buffer_size());
+        // (like the continuous decoding loop, for 12s of audio)
+        for _ in 0..120 {
+            let block = vec![0i16; BLOCK];
+            stream.feed_audio(&block);
+            segmenter.push(&block);
+            if segmenter.needs_refresh() {
+                stream = next_stream(stream, &mut recognizer, &mut scorer, &mut segmenter, &mut dispatcher, &casl_config, &pipeline);
+            }
+        }
+        // "spell" is heard in the first stream, so only the second stream uses the spelling scorer
+        assert_eq!(recognizer.streams, vec![
+            Some("general.scorer".to_owned()),
+            Some("nato.scorer".to_owned()),
+            Some("general.scorer".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn splits_words_at_whitespace_tokens() {
+        let casl_config = config::Config::mock(r#"[{"phrases": [{"text": "hi there", "start_ms": 1000}]}]"#);
+        let pipeline = Pipeline::new(&casl_config).expect("Invalid test pipeline");
+        let mut stream = casl_config.recognizer().create_stream();
+        stream.feed_audio(&vec![0i16; 32_000]);
+        let metadata = stream.finish_with_metadata(1);
+        let utterances = process_utterances(&metadata, 2_000, 500, &casl_config, &pipeline);
+        assert_eq!(utterances.len(), 1);
+        let words: Vec<(&str, u64, u64)> = utterances[0].result.words.iter()
+            .map(|w| (w.text.as_str(), w.start_ms, w.end_ms))
+            .collect();
+        assert_eq!(words, vec![("hi", 1_500, 1_540), ("there", 1_560, 1_660)]);
+    }
+
+    #[test]
+    fn handles_no_transcripts() {
+        let casl_config = config::Config::mock("[]");
+        let pipeline = Pipeline::new(&casl_config).expect("Invalid test pipeline");
+        let metadata = Metadata { transcripts: Vec::new() };
+        let utterances = process_utterances(&metadata, 2_000, 0, &casl_config, &pipeline);
+        assert_eq!(utterances.len(), 1);
+        assert_eq!(utterances[0].result.phrase_raw, "");
+        assert!(utterances[0].result.alternates.is_empty());
+    }
+
+    #[test]
+    fn rejects_zero_candidates() {
+        let mut casl_config = config::Config::mock("[]");
+        casl_config.candidates = 0;
+        assert!(Pipeline::new(&casl_config).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use crate::config::PreprocessorConfig;
+use crate::preprocessor::{ITextPreprocessor, split_words, split_phrases, starts_with_phrase, longest_match};
+
+// (spoken words, written text) -- NATO alphabet, including the ways deepspeech tends to hear it
+const LETTERS: [(&str, &str); 34] = [
+    ("alpha", "a"), ("alfa", "a"), ("bravo", "b"), ("charlie", "c"), ("delta", "d"), ("echo", "e"),
+    ("foxtrot", "f"), ("fox trot", "f"), ("golf", "g"), ("gulf", "g"), ("hotel", "h"), ("india", "i"),
+    ("juliett", "j"), ("juliet", "j"), ("juliette", "j"), ("kilo", "k"), ("lima", "l"), ("mike", "m"),
+    ("november", "n"), ("oscar", "o"), ("papa", "p"), ("quebec", "q"), ("romeo", "r"), ("rome", "r"),
+    ("sierra", "s"), ("tango", "t"), ("uniform", "u"), ("victor", "v"), ("whiskey", "w"), ("whisky", "w"),
+    ("xray", "x"), ("x ray", "x"), ("yankee", "y"), ("zulu", "z"),
+];
+const DIGITS: [&str; 10] = ["zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine"];
+const SYMBOLS: [(&str, &str); 4] = [("space", " "), ("dash", "-"), ("dot", "."), ("underscore", "_")];
+const CAPITAL: &str = "capital";
+
+enum Symbol<'a> {
+    Text(&'a str),
+    Capital, // the next letter is upper case
+}
+
+// Joins spelled out words between a start and stop phrase into a single word,
+// e.g. "spell november golf capital november india uniform sierra stop" -> "ngNius"
+pub struct Speller {
+    start: Vec<Vec<String>>,
+    stop: Vec<Vec<String>>,
+    // (spoken words, written text or None for capital), longest first so that "x ray" wins over "x"
+    symbols: Vec<(Vec<String>, Option<String>)>,
+}
+
+impl Speller {
+    pub fn new(conf: &PreprocessorConfig) -> Speller {
+        if let PreprocessorConfig::Spell { start, stop, words } = conf {
+            let mut symbols: Vec<(Vec<String>, Option<String>)> = Vec::new();
+            // custom words come first, so that they win over the built in ones
+            for (spoken, written) in words {
+                symbols.push((split_words(spoken), Some(written.clone())));
+            }
+            for (spoken, written) in LETTERS.iter().chain(SYMBOLS.iter()) {
+                symbols.push((split_words(spoken), Some((*written).to_owned())));
+            }
+            for (digit, spoken) in DIGITS.iter().enumerate() {
+                symbols.push((split_words(spoken), Some(digit.to_string())));
+            }
+            symbols.push((split_words(CAPITAL), None));
+            symbols.retain(|(spoken, _)| !spoken.is_empty());
+            symbols.sort_by_key(|(spoken, _)| std::cmp::Reverse(spoken.len())); // (stable, so custom words stay first)
+            Speller {
+                start: split_phrases(start),
+                stop: split_phrases(stop),
+                symbols,
+            }
+        } else {panic!("Non-Spell config given to Speller");}
+    }
+
+    // the symbol spelled at the start of words, and how many words it uses
+    fn symbol<'a>(&'a self, words: &'a [String]) -> Option<(Symbol<'a>, usize)> {
+        if let Some((spoken, written)) = self.symbols.iter().find(|(spoken, _)| starts_with_phrase(words, spoken)) {
+            let symbol = match written {
+                Some(text) => Symbol::Text(text),
+                None => Symbol::Capital,
+            };
+            return Some((symbol, spoken.len()));
+        }
+        // plain letters and digits (e.g. from a Remap of the NATO alphabet, or the Numbers pre-processor)
+        match words.first() {
+            Some(w) if w.chars().count() == 1 && w.chars().all(char::is_alphanumeric) => Some((Symbol::Text(w), 1)),
+            _ => None,
+        }
+    }
+}
+
+impl ITextPreprocessor for Speller {
+    fn process(&self, input: &str) -> String {
+        let originals: Vec<&str> = input.split_whitespace().collect();
+        let words: Vec<String> = originals.iter().map(|w| w.to_lowercase()).collect();
+        let mut result: Vec<String> = Vec::with_capacity(words.len());
+        let mut i = 0;
+        while i < words.len() {
+            let start_len = match longest_match(&self.start, &words[i..]) {
+                Some(n) => n,
+                None => {
+                    result.push(originals[i].to_owned());
+                    i += 1;
+                    continue;
+                }
+            };
+            let mut j = i + start_len;
+            let mut spelled = String::new();
+            let mut is_capital = false;
+            // spelling ends at a stop phrase, or at the first word which can't be spelled
+            while j < words.len() {
+                if let Some(n) = longest_match(&self.stop, &words[j..]) {
+                    j += n;
+                    break;
+                }
+                match self.symbol(&words[j..]) {
+                    Some((Symbol::Capital, n)) => {
+                        is_capital = true;
+                        j += n;
+                    },
+                    Some((Symbol::Text(text), n)) => {
+                        if is_capital {
+                            spelled.push_str(&text.to_uppercase());
+                        } else {
+                            spelled.push_str(text);
+                        }
+                        is_capital = false;
+                        j += n;
+                    },
+                    None => break,
+                }
+            }
+            if spelled.is_empty() {
+                // nothing was spelled, so the start phrase was just a word
+                result.push(originals[i].to_owned());
+                i += 1;
+            } else {
+                result.push(spelled);
+                i = j;
+            }
+        }
+        result.join(" ")
+    }
+}
@@ -0,0 +1,52 @@
+use std::io::{BufRead, IsTerminal};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use rustyline::error::ReadlineError;
+
+use crate::config::Config;
+use crate::pipeline::Pipeline;
+
+const PROMPT: &str = "casl> ";
+const LINE_WAIT_MS: u64 = 250; // wait for commands to respond before the next line
+
+// Read phrases from the terminal (or piped stdin) instead of listening for them
+pub fn process_text_loop(casl_config: &Config, pipeline: &Pipeline) {
+    let mut pending = Vec::new();
+    if std::io::stdin().is_terminal() {
+        let mut editor = rustyline::Editor::<()>::new();
+        loop {
+            match editor.readline(PROMPT) {
+                Ok(line) => {
+                    editor.add_history_entry(line.as_str());
+                    pending = process_line(&line, pending, casl_config, pipeline);
+                },
+                Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    println!("Failed to read line: {}", e);
+                    break;
+                }
+            }
+        }
+    } else {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => pending = process_line(&line, pending, casl_config, pipeline),
+                Err(e) => {
+                    println!("Failed to read line: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+    crate::command::wait_before_exit(pending, casl_config);
+}
+
+fn process_line(line: &str, mut pending: Vec<JoinHandle<()>>, casl_config: &Config, pipeline: &Pipeline) -> Vec<JoinHandle<()>> {
+    let text = line.trim();
+    if text.is_empty() {
+        return pending;
+    }
+    let meta = crate::speech::process_text(text, pipeline);
+    pending.extend(crate::command::process_commands(&meta, casl_config, pipeline).threads);
+    crate::command::wait_for_commands(pending, Duration::from_millis(LINE_WAIT_MS))
+}
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+
+use crate::TARGET_SAMPLE_RATE;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VadConfig {
+    #[serde(default)]
+    pub mode: VadMode,
+    // Energy: minimum frame energy (dBFS) to count as speech
+    // Adaptive: minimum frame energy above the background noise level (dB) to count as speech
+    pub threshold_db: f32,
+    // frames which cross zero more often than this (crossings per sample) are treated as noise (e.g. hiss)
+    #[serde(default)]
+    pub max_zero_crossing_rate: Option<f32>,
+    #[serde(default = "default_frame_ms")]
+    pub frame_ms: u32,
+    #[serde(default = "default_min_speech_ms")]
+    pub min_speech_ms: u32,
+    #[serde(default = "default_hangover_ms")]
+    pub hangover_ms: u32,
+    #[serde(default = "default_pre_roll_ms")]
+    pub pre_roll_ms: u32,
+    #[serde(default = "default_max_speech_ms")]
+    pub max_speech_ms: u32,
+}
+
+fn default_frame_ms() -> u32 { 20 }
+
+fn default_min_speech_ms() -> u32 { 100 }
+
+fn default_hangover_ms() -> u32 { 600 }
+
+fn default_pre_roll_ms() -> u32 { 300 }
+
+fn default_max_speech_ms() -> u32 { 20_000 }
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum VadMode {
+    #[default]
+    Energy, // fixed threshold
+    Adaptive, // threshold relative to the tracked background noise level
+}
+
+pub enum VadEvent {
+    SpeechStart,
+    SpeechEnd,
+}
+
+const NOISE_FLOOR_RISE_DB: f32 = 0.05; // per frame, so that speech doesn't raise the noise floor much
+const NOISE_FLOOR_FALL_RATE: f32 = 0.1; // fraction of the difference per frame
+const INITIAL_NOISE_FLOOR_DB: f32 = -60.0;
+
+// Energy and zero-crossing based voice activity detector with hangover
+pub struct VoiceActivityDetector {
+    conf: VadConfig,
+    frame_size: usize,
+    frame: Vec<i16>, // incomplete frame left over from the last call
+    is_speaking: bool,
+    speech_frames: u32, // consecutive speech frames while silent
+    silent_frames: u32, // consecutive silent frames while speaking
+    total_frames: u32, // frames since speech started
+    noise_floor_db: f32,
+}
+
+impl VoiceActivityDetector {
+    pub fn new(conf: &VadConfig) -> VoiceActivityDetector {
+        let frame_size = (conf.frame_ms * TARGET_SAMPLE_RATE / 1_000) as usize;
+        VoiceActivityDetector {
+            conf: conf.clone(),
+            frame_size,
+            frame: Vec::with_capacity(frame_size),
+            is_speaking: false,
+            speech_frames: 0,
+            silent_frames: 0,
+            total_frames: 0,
+            noise_floor_db: INITIAL_NOISE_FLOOR_DB,
+        }
+    }
+
+    // samples to keep from before the start of speech is detected
+    pub fn lookback_samples(&self) -> usize {
+        ((self.conf.pre_roll_ms + self.conf.min_speech_ms) * TARGET_SAMPLE_RATE / 1_000) as usize
+    }
+
+    // classify samples; events are returned with the sample index (in samples) where they happen
+    pub fn process(&mut self, samples: &[i16]) -> Vec<(usize, VadEvent)> {
+        let mut events = Vec::new();
+        for (i, &sample) in samples.iter().enumerate() {
+            self.frame.push(sample);
+            if self.frame.len() == self.frame_size {
+                if let Some(event) = self.process_frame() {
+                    events.push((i + 1, event));
+                }
+                self.frame.clear();
+            }
+        }
+        events
+    }
+
+    fn process_frame(&mut self) -> Option<VadEvent> {
+        let is_speech = self.is_speech_frame();
+        let frame_ms = self.conf.frame_ms;
+        let frames_in = |ms: u32| (ms / frame_ms).max(1);
+        if self.is_speaking {
+            self.total_frames += 1;
+            self.silent_frames = if is_speech { 0 } else { self.silent_frames + 1 };
+            if self.silent_frames >= frames_in(self.conf.hangover_ms)
+                || self.total_frames >= frames_in(self.conf.max_speech_ms) {
+                self.is_speaking = false;
+                self.speech_frames = 0;
+                return Some(VadEvent::SpeechEnd);
+            }
+        } else {
+            self.speech_frames = if is_speech { self.speech_frames + 1 } else { 0 };
+            if self.speech_frames >= frames_in(self.conf.min_speech_ms) {
+                self.is_speaking = true;
+                self.silent_frames = 0;
+                self.total_frames = self.speech_frames;
+                return Some(VadEvent::SpeechStart);
+            }
+        }
+        None
+    }
+
+    fn is_speech_frame(&mut self) -> bool {
+        let energy = self.frame.iter()
+            .map(|&s| (s as f64 / i16::MAX as f64).powi(2))
+            .sum::<f64>() / self.frame.len() as f64;
+        let energy_db = (10.0 * energy.max(1e-10).log10()) as f32;
+        let zero_crossings = self.frame.windows(2)
+            .filter(|w| (w[0] < 0) != (w[1] < 0))
+            .count();
+        let zero_crossing_rate = zero_crossings as f32 / self.frame.len() as f32;
+        let is_loud = match self.conf.mode {
+            VadMode::Energy => energy_db > self.conf.threshold_db,
+            VadMode::Adaptive => {
+                let is_loud = energy_db > self.noise_floor_db + self.conf.threshold_db;
+                if !is_loud || !self.is_speaking {
+                    // track the background: follow quieter frames quickly, louder frames slowly
+                    self.noise_floor_db += if energy_db < self.noise_floor_db {
+                        (energy_db - self.noise_floor_db) * NOISE_FLOOR_FALL_RATE
+                    } else {
+                        (energy_db - self.noise_floor_db).min(NOISE_FLOOR_RISE_DB)
+                    };
+                }
+                is_loud
+            }
+        };
+        is_loud && self.conf.max_zero_crossing_rate.map(|max| zero_crossing_rate <= max).unwrap_or(true)
+    }
+}
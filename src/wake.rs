@@ -0,0 +1,267 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender};
+use std::time::{Duration, Instant};
+use regex::{Regex, RegexBuilder};
+
+use crate::config::Config;
+use crate::pipeline::Pipeline;
+use crate::speech::MetadataResult;
+use crate::command_api::Word;
+
+pub const DEFAULT_LISTEN_MS: u64 = 5_000;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WakeConfig {
+    #[serde(default)]
+    pub phrases: Vec<String>,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    pub detector: Option<WakeDetectorConfig>,
+}
+
+fn default_timeout_ms() -> u64 { DEFAULT_LISTEN_MS }
+
+impl WakeConfig {
+    pub fn detector(&self) -> Option<Box<dyn IWakeDetector>> {
+        self.detector.as_ref().map(|d| d.detector(Duration::from_millis(self.timeout_ms)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum WakeDetectorConfig {
+    Process {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    }
+}
+
+impl WakeDetectorConfig {
+    pub fn detector(&self, timeout: Duration) -> Box<dyn IWakeDetector> {
+        match self {
+            WakeDetectorConfig::Process { .. } => Box::new(ProcessWakeDetector::new(self, timeout)),
+        }
+    }
+}
+
+// Listening state, shared with commands (through their payload) and CASL actions
+struct WakeState {
+    is_gated: bool,
+    listening_until: Option<Instant>,
+}
+
+static WAKE_STATE: Mutex<WakeState> = Mutex::new(WakeState {
+    is_gated: false,
+    listening_until: None,
+});
+
+// only act on commands while listening (called on startup when a wake phrase is configured)
+pub fn enable_gate() {
+    WAKE_STATE.lock().unwrap().is_gated = true;
+}
+
+pub fn is_listening() -> bool {
+    let state = WAKE_STATE.lock().unwrap();
+    !state.is_gated || state.listening_until.map(|t| Instant::now() < t).unwrap_or(false)
+}
+
+pub fn start_listening(timeout: Duration) {
+    WAKE_STATE.lock().unwrap().listening_until = Some(Instant::now() + timeout);
+}
+
+pub fn stop_listening() {
+    WAKE_STATE.lock().unwrap().listening_until = None;
+}
+
+// Remove the wake phrase from the utterance, or return None if commands should ignore it
+pub fn wake_filter(meta: &MetadataResult, wake: &WakeConfig, casl_config: &Config, pipeline: &Pipeline) -> Option<MetadataResult> {
+    // every candidate transcript may have heard the wake phrase, and commands may be matched against any of them
+    let mut result = meta.clone();
+    let mut is_heard = false;
+    if let Some(wake_phrase) = &pipeline.wake_phrase {
+        if let Some((rest, words)) = strip_wake_phrase(wake_phrase, &meta.phrase_raw, &meta.words) {
+            is_heard = true;
+            result.phrase = pipeline.preprocess(&rest);
+            result.phrase_raw = rest;
+            result.words = words;
+            if let Some(first) = result.words.first() {
+                result.start_ms = first.start_ms;
+            }
+        }
+        for alternate in &mut result.alternates {
+            if let Some((rest, words)) = strip_wake_phrase(wake_phrase, &alternate.phrase_raw, &alternate.words) {
+                is_heard = true;
+                alternate.phrase = pipeline.preprocess(&rest);
+                alternate.phrase_raw = rest;
+                alternate.words = words;
+            }
+        }
+    }
+    if is_heard {
+        start_listening(Duration::from_millis(wake.timeout_ms));
+        if casl_config.debug {
+            println!("Wake phrase heard, listening for {}ms", wake.timeout_ms);
+        }
+        // the rest of the utterance may be a command
+        if result.phrase_raw.is_empty() && result.alternates.iter().all(|a| a.phrase_raw.is_empty()) {
+            return None;
+        }
+        return Some(result);
+    }
+    if is_listening() {
+        Some(result)
+    } else {
+        if casl_config.debug {
+            println!("Ignoring `{}` (not listening)", &meta.phrase_raw);
+        }
+        None
+    }
+}
+
+// the text (and its words) after the first wake phrase, if there is one
+fn strip_wake_phrase(wake_phrase: &Regex, text: &str, words: &[Word]) -> Option<(String, Vec<Word>)> {
+    let end = wake_phrase.find(text)?.end();
+    let wake_words = text[..end].split_whitespace().count().min(words.len());
+    Some((text[end..].trim().to_owned(), words[wake_words..].to_vec()))
+}
+
+// matches any of the wake phrases (None if there are no wake phrases)
+pub fn wake_phrase_regex(phrases: &[String]) -> Result<Option<Regex>, String> {
+    if phrases.is_empty() {
+        return Ok(None);
+    }
+    let pattern = phrases.iter()
+        .map(|p| regex::escape(p.trim()).replace(' ', r"\s+"))
+        .collect::<Vec<String>>()
+        .join("|");
+    RegexBuilder::new(&format!(r"\b(?:{})\b", pattern))
+        .case_insensitive(true)
+        .build()
+        .map(Some)
+        .map_err(|e| format!("failed to compile the regex for wake phrases ({})", e))
+}
+
+// Acoustic wake word detector, fed with all captured audio
+pub trait IWakeDetector {
+    fn feed(&mut self, samples: &[i16]);
+}
+
+// Wake word detector running as a child process.
+// Audio is written to its stdin (16kHz mono signed 16-bit little-endian)
+// and every line it writes to stdout counts as a detected wake word.
+pub struct ProcessWakeDetector {
+    audio_tx: Sender<Vec<i16>>,
+    child: Child,
+}
+
+impl ProcessWakeDetector {
+    pub fn new(conf: &WakeDetectorConfig, timeout: Duration) -> ProcessWakeDetector {
+        let WakeDetectorConfig::Process { command, args } = conf;
+        let mut child = Command::new(command)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|e| panic!("Failed to start wake word detector {} ({})", command, e));
+        let mut stdin_writer = BufWriter::new(child.stdin.take().unwrap());
+        let stdout_reader = BufReader::new(child.stdout.take().unwrap());
+        let (audio_tx, audio_rx) = channel::<Vec<i16>>();
+        // write audio in another thread, so that a slow detector doesn't hold up audio processing
+        std::thread::spawn(move || {
+            for block in audio_rx {
+                let bytes: Vec<u8> = block.iter().flat_map(|s| s.to_le_bytes().to_vec()).collect();
+                if stdin_writer.write_all(&bytes).and_then(|_| stdin_writer.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+        let command_name = command.clone();
+        std::thread::spawn(move || {
+            for line in stdout_reader.lines() {
+                if line.is_err() {
+                    break;
+                }
+                println!("Wake word detected by {}", &command_name);
+                start_listening(timeout);
+            }
+        });
+        ProcessWakeDetector {
+            audio_tx,
+            child,
+        }
+    }
+}
+
+impl IWakeDetector for ProcessWakeDetector {
+    fn feed(&mut self, samples: &[i16]) {
+        self.audio_tx.send(samples.to_vec()).unwrap_or(()); // ignore errors
+    }
+}
+
+impl Drop for ProcessWakeDetector {
+    fn drop(&mut self) {
+        self.child.kill().unwrap_or(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::speech::{process_text, CandidateResult};
+
+    // puts the listening state back when a test is done with it (even if it fails),
+    // since every test in the binary shares it
+    struct SavedWakeState {
+        is_gated: bool,
+        listening_until: Option<Instant>,
+    }
+
+    impl SavedWakeState {
+        fn save() -> SavedWakeState {
+            let state = WAKE_STATE.lock().unwrap();
+            SavedWakeState {
+                is_gated: state.is_gated,
+                listening_until: state.listening_until,
+            }
+        }
+    }
+
+    impl Drop for SavedWakeState {
+        fn drop(&mut self) {
+            let mut state = WAKE_STATE.lock().unwrap();
+            state.is_gated = self.is_gated;
+            state.listening_until = self.listening_until;
+        }
+    }
+
+    #[test]
+    fn strips_wake_phrase_from_every_candidate() {
+        let mut casl_config = Config::mock("[]");
+        casl_config.wake = Some(serde_json::from_str(r#"{"phrases": ["hey casl"]}"#).expect("Invalid test wake config"));
+        let pipeline = Pipeline::new(&casl_config).expect("Invalid test pipeline");
+        let mut meta = process_text("hey castle open the browser", &pipeline);
+        let alternate = process_text("hey casl open the browser", &pipeline);
+        meta.alternates.push(CandidateResult {
+            phrase_raw: alternate.phrase_raw,
+            phrase: alternate.phrase,
+            confidence: alternate.confidence,
+            words: alternate.words,
+        });
+        let _saved = SavedWakeState::save();
+        enable_gate();
+        stop_listening();
+        // (only the alternate heard the wake phrase, which is enough to start listening)
+        let result = wake_filter(&meta, casl_config.wake.as_ref().unwrap(), &casl_config, &pipeline)
+            .expect("Wake phrase in an alternate was ignored");
+        assert_eq!(result.phrase_raw, "hey castle open the browser");
+        assert_eq!(result.alternates[0].phrase_raw, "open the browser");
+        assert_eq!(result.alternates[0].phrase, "open the browser");
+        let words: Vec<&str> = result.alternates[0].words.iter().map(|w| w.text.as_str()).collect();
+        assert_eq!(words, vec!["open", "the", "browser"]);
+        assert!(is_listening());
+    }
+}